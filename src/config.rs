@@ -2,7 +2,11 @@ use crate::{login, vfs};
 use anyhow::{Context as _, Result};
 use libc::{gid_t, mode_t, uid_t};
 use serde::{de::Deserializer, Deserialize};
-use std::{path::Path, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    time::Duration,
+};
 
 const DEFAULT_CONFIG: &str = include_str!("../config.default.toml");
 
@@ -20,6 +24,28 @@ pub struct NetConfig {
     pub connect_timeout: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
     pub request_timeout: Duration,
+    pub download_ip_family: IpFamily,
+    pub max_requests_per_sec: u64,
+}
+
+/// The IP protocol used for connections.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    /// The local address to bind, which restricts connections to the family.
+    pub fn local_address(self) -> Option<IpAddr> {
+        match self {
+            Self::Any => None,
+            Self::Ipv4 => Some(Ipv4Addr::UNSPECIFIED.into()),
+            Self::Ipv6 => Some(Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
 }
 
 impl Config {
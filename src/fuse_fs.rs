@@ -1,7 +1,7 @@
 use crate::{config::PermissionConfig, vfs};
 use fuser::{
     FileAttr, FileType, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
 use std::{convert::TryFrom as _, ffi::OsStr, sync::Arc, time::SystemTime};
 
@@ -10,8 +10,6 @@ const NAME_LEN: u32 = 2048;
 const BLOCK_SIZE: u32 = 512;
 const FRAGMENT_SIZE: u32 = 512;
 
-const READDIR_CHUNK_SIZE: usize = 64;
-
 pub struct Filesystem {
     inner: Arc<FilesystemInner>,
 }
@@ -58,7 +56,9 @@ impl FilesystemInner {
             } else {
                 self.perm_config.file_permission()
             } as _,
-            nlink: 1,
+            // Only empty directories are known to have no sub-directories. Otherwise, report 1 as
+            // unknown count, so that tools like `find` don't rely on it.
+            nlink: if attr.empty_dir { 2 } else { 1 },
             uid: self.perm_config.uid as _,
             gid: self.perm_config.gid as _,
             rdev: 0,
@@ -68,6 +68,27 @@ impl FilesystemInner {
     }
 }
 
+/// The attribute of negative entries, where only the zero inode matters.
+fn negative_attr() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
 impl fuser::Filesystem for Filesystem {
     fn init(
         &mut self,
@@ -76,10 +97,46 @@ impl fuser::Filesystem for Filesystem {
     ) -> std::result::Result<(), libc::c_int> {
         log::info!("FUSE initialized");
         let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+
+        // Report syncing state as service status.
+        let mut syncing_rx = self.inner.vfs.subscribe_syncing();
+        self.spawn(|_| async move {
+            loop {
+                let status = if *syncing_rx.borrow_and_update() {
+                    "Syncing"
+                } else {
+                    "Idle"
+                };
+                let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Status(status)]);
+                if syncing_rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
         Ok(())
     }
 
     fn destroy(&mut self) {
+        if self.inner.vfs.is_syncing() {
+            log::warn!("FUSE destroyed while some transfers are still in progress");
+        }
+        let open_files = self.inner.vfs.open_files();
+        if !open_files.is_empty() {
+            let list = open_files
+                .iter()
+                .map(|(_, item_id)| item_id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            log::warn!(
+                "FUSE destroyed with {} files still open: {}",
+                open_files.len(),
+                list,
+            );
+        }
+        let dirty = self.inner.vfs.dirty_file_count();
+        if dirty != 0 {
+            log::warn!("FUSE destroyed with {} files not uploaded yet", dirty);
+        }
         log::info!("FUSE destroyed");
     }
 
@@ -105,6 +162,11 @@ impl fuser::Filesystem for Filesystem {
         let name = name.to_owned();
         self.spawn(|inner| async move {
             match inner.vfs.lookup(parent, &name).await {
+                Err(vfs::Error::NotFound) => match inner.vfs.negative_ttl() {
+                    // Reply a negative entry, which is cached by the kernel as a missing name.
+                    Some(ttl) => reply.entry(&ttl, &negative_attr(), GENERATION),
+                    None => reply.error(vfs::Error::NotFound.into_c_err()),
+                },
                 Err(err) => reply.error(err.into_c_err()),
                 Ok((ino, attr, ttl)) => {
                     let attr = inner.cvt_attr(ino, attr);
@@ -132,6 +194,25 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_owned();
+        self.spawn(|inner| async move {
+            match inner.vfs.get_xattr(ino, &name).await {
+                Ok(value) => reply_xattr(reply, size, &value),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.spawn(|inner| async move {
+            match inner.vfs.list_xattr(ino).await {
+                Ok(names) => reply_xattr(reply, size, &names),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
     fn access(&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         reply.ok();
     }
@@ -161,35 +242,40 @@ impl fuser::Filesystem for Filesystem {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        // `.` and `..` take the first two offsets.
+        const DOT_ENTRIES: u64 = 2;
         let offset = u64::try_from(offset).unwrap();
         self.spawn(|inner| async move {
-            match inner
-                .vfs
-                .read_dir(ino, fh, offset, READDIR_CHUNK_SIZE)
-                .await
-            {
-                Err(err) => reply.error(err.into_c_err()),
-                Ok(entries) => {
-                    for (idx, entry) in entries.as_ref().iter().enumerate() {
-                        let next_offset = offset
-                            .checked_add(u64::try_from(idx).unwrap())
-                            .unwrap()
-                            .checked_add(1)
-                            .unwrap();
-                        let kind = if entry.attr.is_directory {
-                            FileType::Directory
-                        } else {
-                            FileType::RegularFile
-                        };
-                        // Inode id here is useless and further `lookup` will still be called.
-                        // But it still need to be not zero.
-                        if reply.add(u64::MAX, next_offset as i64, kind, &entry.name) {
-                            break;
-                        }
-                    }
+            for (next_offset, name) in [(1, "."), (DOT_ENTRIES, "..")] {
+                // Inode id of `..` is as useless as other entries below.
+                if offset < next_offset
+                    && reply.add(ino, next_offset as i64, FileType::Directory, name)
+                {
                     reply.ok();
+                    return;
                 }
             }
+            // Entries are directly fed into the reply buffer until it's full.
+            // Attributes are not returned along, since the lookups and getattrs after listing are
+            // served from the synced tree without round-trips anyway.
+            let offset = offset.saturating_sub(DOT_ENTRIES);
+            let ret = inner
+                .vfs
+                .read_dir(ino, fh, offset, |next_offset, name, attr| {
+                    let kind = if attr.is_directory {
+                        FileType::Directory
+                    } else {
+                        FileType::RegularFile
+                    };
+                    // Inode id here is useless and further `lookup` will still be called.
+                    // But it still need to be not zero.
+                    reply.add(u64::MAX, (DOT_ENTRIES + next_offset) as i64, kind, name)
+                })
+                .await;
+            match ret {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok(()) => reply.ok(),
+            }
         });
     }
 
@@ -201,10 +287,17 @@ impl fuser::Filesystem for Filesystem {
         let write = (flags & libc::O_WRONLY) != 0;
         assert_eq!(flags & libc::O_TRUNC, 0);
         let ret_flags = flags & libc::O_WRONLY;
+        // True direct I/O is impossible, but bypass the page cache and speculative reads.
+        let direct = (flags & libc::O_DIRECT) != 0;
 
         self.spawn(|inner| async move {
-            match inner.vfs.open_file(ino, write).await {
-                Ok(fh) => reply.opened(fh, ret_flags as u32),
+            let ret_flags = if direct || inner.vfs.is_virtual_file(ino) {
+                ret_flags as u32 | fuser::consts::FOPEN_DIRECT_IO
+            } else {
+                ret_flags as u32
+            };
+            match inner.vfs.open_file(ino, write, direct).await {
+                Ok(fh) => reply.opened(fh, ret_flags),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
@@ -313,14 +406,23 @@ impl fuser::Filesystem for Filesystem {
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        // TODO: Handle flags.
+        // Exchanging is not supported by OneDrive.
+        let no_replace = match flags {
+            0 => false,
+            libc::RENAME_NOREPLACE => true,
+            _ => return reply.error(libc::EINVAL),
+        };
         let name = name.to_owned();
         let newname = newname.to_owned();
         self.spawn(|inner| async move {
-            match inner.vfs.rename(parent, &name, newparent, &newname).await {
+            match inner
+                .vfs
+                .rename(parent, &name, newparent, &newname, no_replace)
+                .await
+            {
                 Ok(_) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -402,16 +504,36 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    fn fsyncdir(
+    fn fallocate(
         &mut self,
-        _req: &Request,
-        _ino: u64,
-        _fh: u64,
-        _datasync: bool,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
         reply: ReplyEmpty,
     ) {
-        // Currently we don't delay inode changes, so this is trivial.
-        reply.ok();
+        self.spawn(|inner| async move {
+            match inner
+                .vfs
+                .fallocate(ino, fh, offset as u64, length as u64, mode)
+                .await
+            {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        // Inode changes are not delayed, but uploads of files in it are.
+        self.spawn(|inner| async move {
+            match inner.vfs.sync_dir(ino).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
     }
 
     fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
@@ -424,8 +546,20 @@ impl fuser::Filesystem for Filesystem {
     }
 }
 
+/// Reply the size if `size` is zero, or the data if it fits in `size`.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    let len = u32::try_from(data.len()).unwrap();
+    if size == 0 {
+        reply.size(len);
+    } else if size < len {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
 fn to_blocks_ceil(bytes: u64) -> u64 {
-    (bytes + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64
+    bytes.div_ceil(BLOCK_SIZE as u64)
 }
 
 fn to_blocks_floor(bytes: u64) -> u64 {
@@ -1,4 +1,4 @@
-use crate::config::de_duration_sec;
+use crate::{config::de_duration_sec, vfs::rate_limit::RateLimiter};
 use anyhow::{ensure, Context as _, Result};
 use onedrive_api::{Auth, DriveLocation, OneDrive, Permission};
 use serde::{Deserialize, Serialize};
@@ -27,6 +27,8 @@ pub struct ReloginConfig {
 #[derive(Clone)]
 pub struct ManagedOnedrive {
     onedrive: Arc<RwLock<OneDrive>>,
+    /// Global limiter for all API requests.
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl ManagedOnedrive {
@@ -35,6 +37,7 @@ impl ManagedOnedrive {
         credential_file: PathBuf,
         config: ReloginConfig,
         mount_readonly: bool,
+        max_requests_per_sec: u64,
     ) -> Result<Self> {
         log::info!("Logining...");
         let mut cred = Credential::load(&credential_file).context(
@@ -80,7 +83,10 @@ impl ManagedOnedrive {
             ));
         }
 
-        Ok(Self { onedrive })
+        Ok(Self {
+            onedrive,
+            limiter: RateLimiter::new(max_requests_per_sec).map(Arc::new),
+        })
     }
 
     async fn relogin_thread(
@@ -147,7 +153,11 @@ impl ManagedOnedrive {
         }
     }
 
+    /// Get the client for an API request. It waits if `net.max_requests_per_sec` is exceeded.
     pub async fn get(&self) -> RwLockReadGuard<'_, OneDrive> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(1).await;
+        }
         self.onedrive.read().await
     }
 }
@@ -172,7 +182,7 @@ impl Credential {
         let parent = path.parent().context("Invalid credential path")?;
         fs::create_dir_all(parent)?;
 
-        let tmp_path = if path.extension().map_or(false, |ext| ext == "tmp") {
+        let tmp_path = if path.extension().is_some_and(|ext| ext == "tmp") {
             path.with_extension("_tmp")
         } else {
             path.with_extension("tmp")
@@ -174,10 +174,17 @@ async fn main_mount(opt: OptMount) -> Result<()> {
     let unlimit_client = reqwest::ClientBuilder::new()
         .https_only(true)
         .connect_timeout(config.net.connect_timeout)
+        .local_address(config.net.download_ip_family.local_address())
         .build()?;
 
-    let onedrive =
-        ManagedOnedrive::login(client, credential_path, config.relogin, readonly).await?;
+    let onedrive = ManagedOnedrive::login(
+        client,
+        credential_path,
+        config.relogin,
+        readonly,
+        config.net.max_requests_per_sec,
+    )
+    .await?;
     let vfs = vfs::Vfs::new(
         fuser::FUSE_ROOT_ID,
         readonly,
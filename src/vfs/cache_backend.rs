@@ -0,0 +1,48 @@
+//! Storage backends where cache files are allocated.
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use serde::Deserialize;
+use std::{fmt, fs::File, io, os::unix::io::FromRawFd as _, path::PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Disk,
+    Memory,
+}
+
+/// Allocator of anonymous cache files.
+///
+/// Allocated files must support positioned reads and writes, and are removed once closed.
+pub trait CacheBackend: fmt::Debug + Send + Sync {
+    fn alloc(&self) -> io::Result<File>;
+}
+
+/// Unnamed temporary files under a directory.
+#[derive(Debug)]
+pub struct DiskBackend {
+    dir: PathBuf,
+}
+
+impl DiskBackend {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl CacheBackend for DiskBackend {
+    fn alloc(&self) -> io::Result<File> {
+        tempfile::tempfile_in(&self.dir)
+    }
+}
+
+/// Anonymous memory-backed files, which behave like files on tmpfs.
+#[derive(Debug)]
+pub struct MemoryBackend;
+
+impl CacheBackend for MemoryBackend {
+    fn alloc(&self) -> io::Result<File> {
+        let fd = memfd_create(c"onedrive_fuse-cache", MemFdCreateFlag::MFD_CLOEXEC)?;
+        // SAFETY: The fd is just created and owned by nobody else.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
@@ -16,12 +16,22 @@ pub enum Error {
     DirectoryNotEmpty,
     #[error("Invalid file name: {}", .0.to_string_lossy())]
     InvalidFileName(OsString),
+    #[error("File name too long")]
+    NameTooLong,
     #[error("File exists")]
     FileExists,
     #[error("File changed in remote side, please re-open it")]
     Invalidated,
     #[error("File is uploading, you cannot move or remove it")]
     Uploading,
+    #[error("File is read-only")]
+    ReadOnlyFile,
+    #[error("No such attribute")]
+    NoAttribute,
+    #[error("Too many levels of directories")]
+    PathLoop,
+    #[error("File is too large to allocate")]
+    AllocTooLarge,
 
     // Api and network errors.
     #[error("Api error: {0}")]
@@ -32,6 +42,12 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("Download failed")]
     DownloadFailed,
+    #[error("{0}")]
+    Transfer(Box<crate::vfs::file::TransferError>),
+    #[error("Drive is full")]
+    QuotaExceeded,
+    #[error("Invalid metadata: {0:#}")]
+    InvalidMetadata(anyhow::Error),
 
     // IO error.
     #[error("IO error: {0}")]
@@ -48,6 +64,10 @@ pub enum Error {
     FileTooLarge,
     #[error("File writing is not supported without disk cache")]
     WriteWithoutCache,
+    #[error("Item has no content to download")]
+    NotAFile,
+    #[error("Operation not supported")]
+    Unsupported,
 
     // Fuse errors.
     // They are hard errors here, since `fuse` should guarantee that they are valid.
@@ -78,25 +98,39 @@ impl Error {
             Self::FileExists => libc::EEXIST,
             Self::Invalidated => libc::EPERM,
             Self::Uploading => libc::ETXTBSY,
+            Self::ReadOnlyFile => libc::EACCES,
+            Self::NoAttribute => libc::ENODATA,
+            Self::NameTooLong => libc::ENAMETOOLONG,
+            Self::PathLoop => libc::ELOOP,
+            Self::AllocTooLarge => libc::EFBIG,
             Self::InvalidFileName(_) => {
                 log::info!("{}", self);
                 libc::EINVAL
             }
 
             // Network errors.
-            Self::Api(_) | Self::Deserialize(_) | Self::Reqwest(_) | Self::Io(_) => {
+            Self::Api(_)
+            | Self::Deserialize(_)
+            | Self::Reqwest(_)
+            | Self::InvalidMetadata(_)
+            | Self::Io(_) => {
                 log::error!("{}", self);
                 log::debug!("{:?}", self);
                 libc::EIO
             }
             // Already reported.
-            Self::DownloadFailed => libc::EIO,
+            Self::DownloadFailed | Self::Transfer(_) => libc::EIO,
+            Self::QuotaExceeded => libc::ENOSPC,
 
             // Not supported
-            Self::NonsequentialRead { .. } | Self::FileTooLarge | Self::WriteWithoutCache => {
+            Self::NonsequentialRead { .. }
+            | Self::FileTooLarge
+            | Self::WriteWithoutCache
+            | Self::NotAFile => {
                 log::info!("{}", self);
                 libc::EPERM
             }
+            Self::Unsupported => libc::EOPNOTSUPP,
 
             // Fuse errors.
             Self::InvalidInode(_) | Self::InvalidHandle(_) => {
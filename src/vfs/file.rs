@@ -2,35 +2,46 @@ use crate::{
     config::de_duration_sec,
     login::ManagedOnedrive,
     paths::default_disk_cache_dir,
-    vfs::{Error, Result, UpdateEvent},
+    vfs::{quick_xor::QuickXorHash, rate_limit::RateLimiter, Error, Result, UpdateEvent},
 };
 use bytes::{Bytes, BytesMut};
 use lru_cache::LruCache;
 use onedrive_api::{
     option::DriveItemPutOption,
     resource::{DriveItem, DriveItemField},
-    ConflictBehavior, ItemId, ItemLocation, OneDrive, Tag,
+    ConflictBehavior, ItemId, ItemLocation, OneDrive, Tag, UploadSession,
 };
 use reqwest::{header, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sharded_slab::Slab;
 use std::{
+    collections::{HashMap, VecDeque},
     convert::TryFrom as _,
+    fmt,
     io::{self, SeekFrom},
-    path::PathBuf,
+    ops::Range,
+    os::unix::{fs::FileExt as _, io::AsRawFd as _},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex as SyncMutex, Weak,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex as SyncMutex, RwLock as SyncRwLock, Weak,
     },
     time::{Duration, Instant, SystemTime},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::{mpsc, oneshot, watch, Mutex, MutexGuard},
+    sync::{
+        mpsc, oneshot, watch, Mutex, MutexGuard, Notify, OwnedSemaphorePermit, Semaphore,
+        SemaphorePermit,
+    },
     time,
 };
 
-use super::InodeAttr;
+use super::{
+    cache_backend::{BackendKind, CacheBackend, DiskBackend, MemoryBackend},
+    inode::Deletion,
+    InodeAttr,
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -45,9 +56,20 @@ struct DownloadConfig {
     #[serde(deserialize_with = "de_duration_sec")]
     retry_delay: Duration,
     stream_buffer_chunks: usize,
+    max_stream_buffer_bytes: usize,
     stream_ring_buffer_size: usize,
+    stream_cursors: usize,
+    lazy_stream_start: bool,
     #[serde(deserialize_with = "de_duration_sec")]
     chunk_timeout: Duration,
+    fail_on_incomplete: bool,
+    #[serde(deserialize_with = "de_duration_sec")]
+    max_processing_wait: Duration,
+    parallel_connections: usize,
+    parallel_min_size: u64,
+    segment_size: u64,
+    max_bytes_per_sec: u64,
+    prefetch_bytes: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -55,9 +77,65 @@ struct DiskCacheConfig {
     enable: bool,
     #[serde(default = "default_disk_cache_dir")]
     path: PathBuf,
+    #[serde(default)]
+    base_path: Option<PathBuf>,
     max_cached_file_size: u64,
     max_files: usize,
     max_total_size: u64,
+    eviction: EvictionPolicy,
+    reuse_read_buffer: bool,
+    short_read: bool,
+    allow_shared_dir: bool,
+    predictive_prefetch: usize,
+    warm_small_on_list: u64,
+    verify_cache_file: bool,
+    verify_hash: bool,
+    read_ahead_workers: usize,
+    revalidate_on_open: bool,
+    backend: BackendKind,
+    persistent: bool,
+    check_consistency: bool,
+}
+
+/// Which file the disk cache drops when it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EvictionPolicy {
+    /// The least recently used one.
+    Lru,
+    /// The least frequently opened one, or the least recently used one among them.
+    Lfu,
+    /// The one with the largest size over its recency rank, so that space is reclaimed with
+    /// fewer evictions.
+    SizeWeighted,
+}
+
+impl EvictionPolicy {
+    /// Remove and return the victim, or `None` if `cache` is empty.
+    fn evict(self, cache: &mut LruCache<ItemId, Arc<FileCache>>) -> Option<Arc<FileCache>> {
+        // `iter` is from the least recently used one.
+        let victim = match self {
+            Self::Lru => return cache.remove_lru().map(|(_, file)| file),
+            Self::Lfu => cache
+                .iter()
+                .min_by_key(|(_, file)| file.opens.load(Ordering::Relaxed))?
+                .0
+                .clone(),
+            Self::SizeWeighted => cache
+                .iter()
+                .enumerate()
+                .map(|(rank, (id, file))| {
+                    let size = file.cached_size.load(Ordering::Relaxed);
+                    (id, size as f64 / (rank + 1) as f64)
+                })
+                // Take the least recently used one among ties.
+                .rev()
+                .max_by(|(_, lhs), (_, rhs)| lhs.total_cmp(rhs))?
+                .0
+                .clone(),
+        };
+        cache.remove(&victim)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -67,16 +145,28 @@ struct UploadConfig {
     flush_delay: Duration,
     #[serde(deserialize_with = "de_duration_sec")]
     retry_delay: Duration,
+    max_bytes_per_sec: u64,
+    background_max_bytes_per_sec: u64,
+    retry_on_reconnect: bool,
+    skip_unchanged: bool,
+    part_size: usize,
+    max_part_buffers: usize,
+    resume_on_restart: bool,
+    #[serde(default)]
+    shadow_dir: Option<PathBuf>,
 }
 
 pub struct FilePool {
-    handles: Slab<File>,
-    disk_cache: Option<DiskCache>,
+    handles: Slab<Handle>,
+    /// Item ids of open handles by `fh`, since `handles` cannot be iterated.
+    open_items: SyncMutex<HashMap<u64, ItemId>>,
+    disk_cache: Option<Arc<DiskCache>>,
     event_tx: mpsc::Sender<UpdateEvent>,
     config: Config,
     onedrive: ManagedOnedrive,
     /// The client without timeout limit, which is used for upload and download.
     client: reqwest::Client,
+    transfers: Arc<TransferTracker>,
 }
 
 #[derive(Debug, Clone)]
@@ -87,11 +177,219 @@ pub struct UpdatedFileAttr {
     pub c_tag: Tag,
 }
 
+/// A snapshot of transfer and cache statistics.
+#[derive(Debug, Clone)]
+pub struct FileStats {
+    pub running_transfers: usize,
+    pub transfers: Vec<TransferInfo>,
+    pub dirty_files: usize,
+    pub cached_files: usize,
+    pub cached_size: u64,
+    pub max_cached_size: u64,
+    /// Opens served by the disk cache, and ones needing a download, since mount.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Files dropped from the disk cache to make room for others, since mount.
+    pub cache_evictions: u64,
+    pub quota_exceeded: bool,
+    pub last_transfer_error: Option<TransferError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Download,
+    Upload,
+}
+
+/// Disk cache usage of files with the same extension.
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionStats {
+    pub cached_files: usize,
+    pub cached_size: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// The group of a file name in `ExtensionStats`, by its lowercase extension.
+fn extension_group(name: Option<&str>) -> String {
+    match name {
+        None => "(unknown)".to_owned(),
+        Some(name) => match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext.to_lowercase(),
+            None => "(none)".to_owned(),
+        },
+    }
+}
+
+/// A snapshot of a running transfer.
+#[derive(Debug, Clone)]
+pub struct TransferInfo {
+    pub kind: TransferKind,
+    pub item_id: ItemId,
+    pub bytes: u64,
+    /// Bytes per second in recent seconds, or `None` if not measured yet.
+    pub speed: Option<f64>,
+    /// Bytes committed on remote side and the total, if known. Only uploads report it.
+    pub progress: Option<(u64, u64)>,
+}
+
+/// A failed attempt of a transfer, with the context to report.
+#[derive(Debug, Clone)]
+pub struct TransferError {
+    pub kind: TransferKind,
+    pub item_id: ItemId,
+    /// The file name, if resolved. It's unknown in this module.
+    pub name: Option<String>,
+    /// The HTTP status of the response, if any.
+    pub status: Option<StatusCode>,
+    /// Number of failed attempts so far, including this one.
+    pub attempt: u32,
+    /// Whether the transfer will be retried.
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            TransferKind::Download => "Download",
+            TransferKind::Upload => "Upload",
+        };
+        match &self.name {
+            Some(name) => write!(f, "{} of {:?} ({})", kind, name, self.item_id.0)?,
+            None => write!(f, "{} of {}", kind, self.item_id.0)?,
+        }
+        write!(f, " failed at attempt {}", self.attempt)?;
+        if let Some(status) = self.status {
+            write!(f, " with HTTP {}", status)?;
+        }
+        let action = if self.retryable {
+            "retrying"
+        } else {
+            "giving up"
+        };
+        write!(f, ", {}: {}", action, self.message)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct RemoteFileMeta {
     size: u64,
     c_tag: Tag,
     download_url: String,
+    quick_xor_hash: Option<String>,
+}
+
+/// Where a file is downloaded from, shared by all downloads of it.
+///
+/// The pre-signed download URL expires in about an hour, so it's fetched again by the item id
+/// once it's rejected, as long as the content is not changed.
+struct DownloadSource {
+    item_id: ItemId,
+    c_tag: Tag,
+    url: Mutex<String>,
+    onedrive: ManagedOnedrive,
+}
+
+impl fmt::Debug for DownloadSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownloadSource")
+            .field("item_id", &self.item_id)
+            .field("c_tag", &self.c_tag)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DownloadSource {
+    fn new(item_id: &ItemId, meta: &RemoteFileMeta, onedrive: ManagedOnedrive) -> Arc<Self> {
+        Arc::new(Self {
+            item_id: item_id.clone(),
+            c_tag: meta.c_tag.clone(),
+            url: Mutex::new(meta.download_url.clone()),
+            onedrive,
+        })
+    }
+
+    async fn url(&self) -> String {
+        self.url.lock().await.clone()
+    }
+
+    /// Replace the `expired` URL with a new one, unless it's already replaced by another
+    /// download. Returns whether it's done.
+    async fn refresh(&self, expired: &str) -> bool {
+        let mut url = self.url.lock().await;
+        if *url != expired {
+            return true;
+        }
+        let loc = ItemLocation::from_id(&self.item_id);
+        let item = match self.onedrive.get().await.get_item(loc).await {
+            Ok(item) => item,
+            Err(err) => {
+                log::error!(
+                    "Failed to refresh download URL of {:?}: {}",
+                    self.item_id,
+                    err,
+                );
+                return false;
+            }
+        };
+        // A new URL would serve the new content, mixed with what is already downloaded.
+        if item.c_tag.as_ref() != Some(&self.c_tag) {
+            log::warn!(
+                "{:?} is modified during download, ctag: {:?} -> {:?}",
+                self.item_id,
+                self.c_tag,
+                item.c_tag,
+            );
+            return false;
+        }
+        match item.download_url {
+            Some(new_url) => {
+                log::info!("Refreshed expired download URL of {:?}", self.item_id);
+                *url = new_url;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn quick_xor_hash_of(item: &DriveItem) -> Option<String> {
+    let hash = item
+        .file
+        .as_ref()?
+        .get("hashes")?
+        .get("quickXorHash")?
+        .as_str()?;
+    Some(hash.to_owned())
+}
+
+/// The start position of `Content-Range: bytes <start>-<end>/<size>`.
+fn content_range_start(headers: &header::HeaderMap) -> Option<u64> {
+    let range = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// The delay requested by `Retry-After`, in either seconds or an HTTP date.
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let time = httpdate::parse_http_date(value).ok()?;
+    // Zero if the date is already passed.
+    Some(time.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// The path of the item relative to the drive root, like `dir/file.txt`.
+fn relative_path_of(item: &DriveItem) -> Option<PathBuf> {
+    let parent = item.parent_reference.as_ref()?.get("path")?.as_str()?;
+    let (_, parent) = parent.split_once("root:")?;
+    let mut path = PathBuf::from(parent.trim_start_matches('/'));
+    path.push(item.name.as_ref()?);
+    Some(path)
 }
 
 impl FilePool {
@@ -101,22 +399,93 @@ impl FilePool {
         event_tx: mpsc::Sender<UpdateEvent>,
         onedrive: ManagedOnedrive,
         unlimit_client: reqwest::Client,
+        account: &str,
         config: Config,
     ) -> anyhow::Result<Self> {
+        // Required by upload sessions, except for the last part.
+        const UPLOAD_PART_ALIGN: usize = 320 << 10;
+        let part_size = config.upload.part_size;
+        anyhow::ensure!(
+            part_size != 0
+                && part_size.is_multiple_of(UPLOAD_PART_ALIGN)
+                && part_size <= onedrive_api::UploadSession::MAX_PART_SIZE,
+            "`vfs.file.upload.part_size` must be a positive multiple of 320 KiB and at most 60 MiB",
+        );
+        anyhow::ensure!(
+            config.upload.max_part_buffers != 0,
+            "`vfs.file.upload.max_part_buffers` must be positive",
+        );
+        anyhow::ensure!(
+            config.download.stream_cursors != 0,
+            "`vfs.file.download.stream_cursors` must be positive",
+        );
+        anyhow::ensure!(
+            config.download.parallel_connections != 0,
+            "`vfs.file.download.parallel_connections` must be positive",
+        );
+
+        let transfers = TransferTracker::new(&config);
+        let disk_cache = if config.disk_cache.enable {
+            let cache = Arc::new(DiskCache::new(config.clone(), account, transfers.clone())?);
+            tokio::spawn(cache.clone().resume_uploads(
+                onedrive.clone(),
+                unlimit_client.clone(),
+                event_tx.clone(),
+            ));
+            Some(cache)
+        } else {
+            None
+        };
         Ok(Self {
             handles: Slab::new(),
-            disk_cache: if config.disk_cache.enable {
-                Some(DiskCache::new(config.clone())?)
-            } else {
-                None
-            },
+            open_items: SyncMutex::new(HashMap::new()),
+            disk_cache,
             event_tx,
             config,
             onedrive,
             client: unlimit_client,
+            transfers,
         })
     }
 
+    /// Whether any download or upload is in progress.
+    pub fn is_syncing(&self) -> bool {
+        *self.transfers.syncing_tx.borrow()
+    }
+
+    /// Subscribe the transitions between syncing and idle state.
+    pub fn subscribe_syncing(&self) -> watch::Receiver<bool> {
+        self.transfers.syncing_tx.subscribe()
+    }
+
+    pub fn stats(&self) -> FileStats {
+        let (cached_files, cached_size, max_cached_size) = match &self.disk_cache {
+            Some(cache) => (
+                cache.cache.lock().unwrap().len(),
+                cache.total_size.load(Ordering::Relaxed),
+                self.config.disk_cache.max_total_size,
+            ),
+            None => (0, 0, 0),
+        };
+        let counter = |f: fn(&DiskCache) -> &AtomicU64| match &self.disk_cache {
+            Some(cache) => f(cache).load(Ordering::Relaxed),
+            None => 0,
+        };
+        FileStats {
+            running_transfers: self.transfers.running.load(Ordering::SeqCst),
+            transfers: self.transfers.list(),
+            dirty_files: self.transfers.dirty_files.load(Ordering::SeqCst),
+            cached_files,
+            cached_size,
+            max_cached_size,
+            cache_hits: counter(|cache| &cache.hits),
+            cache_misses: counter(|cache| &cache.misses),
+            cache_evictions: counter(|cache| &cache.evictions),
+            quota_exceeded: *self.transfers.quota_exceeded.borrow(),
+            last_transfer_error: self.transfers.last_error.lock().unwrap().clone(),
+        }
+    }
+
     fn key_to_fh(key: usize) -> u64 {
         u64::try_from(key).unwrap()
     }
@@ -126,24 +495,141 @@ impl FilePool {
     }
 
     // Fetch file size, CTag and download URL.
-    async fn fetch_meta(item_id: &ItemId, onedrive: &OneDrive) -> Result<RemoteFileMeta> {
-        // `download_url` is available without `$select`.
-        let item = onedrive.get_item(ItemLocation::from_id(item_id)).await?;
+    // The fetched item is also sent to sync, in case it's moved or renamed on remote side
+    // but not tracked yet.
+    async fn fetch_meta(
+        item_id: &ItemId,
+        onedrive: &ManagedOnedrive,
+        config: &DownloadConfig,
+        event_tx: &mpsc::Sender<UpdateEvent>,
+    ) -> Result<RemoteFileMeta> {
+        const PROCESSING_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+        let start_time = Instant::now();
+        let item = loop {
+            // `download_url` is available without `$select`.
+            // Each poll is a separate request, and the client is not held while sleeping,
+            // or a relogin would block all other requests.
+            let item = onedrive
+                .get()
+                .await
+                .get_item(ItemLocation::from_id(item_id))
+                .await?;
+            if item.folder.is_some() {
+                return Err(Error::IsADirectory);
+            }
+            // Hashes and download URL may be unstable when a file is just uploaded.
+            let processing = item
+                .file
+                .as_ref()
+                .and_then(|file| file.get("processingMetadata"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            if !processing {
+                break item;
+            }
+            if config.max_processing_wait <= start_time.elapsed() {
+                log::warn!("{:?} is still processing metadata, use it anyway", item_id);
+                break item;
+            }
+            log::debug!("{:?} is processing metadata, waiting", item_id);
+            time::sleep(PROCESSING_POLL_PERIOD).await;
+        };
+        // Best-effort. Skip it if the sync thread is busy, since the tracker will catch up anyway.
+        if let Err(err) = event_tx.try_send(UpdateEvent::ReconcileItem(Box::new(item.clone()))) {
+            log::warn!(
+                "Skip reconciling {:?} from fetched metadata: {}",
+                item_id,
+                err
+            );
+        }
+        // Packages, eg. OneNote notebooks, have no content stream to download.
+        let download_url = match &item.download_url {
+            Some(url) if item.package.is_none() => url.clone(),
+            _ => return Err(Error::NotAFile),
+        };
+        let missing =
+            |field| Error::InvalidMetadata(anyhow::anyhow!("Missing {} of {:?}", field, item_id));
         Ok(RemoteFileMeta {
-            size: item.size.unwrap() as u64,
-            c_tag: item.c_tag.unwrap(),
-            download_url: item.download_url.unwrap(),
+            quick_xor_hash: quick_xor_hash_of(&item),
+            size: item.size.ok_or_else(|| missing("size"))? as u64,
+            c_tag: item.c_tag.ok_or_else(|| missing("c_tag"))?,
+            download_url,
         })
     }
 
-    async fn open_inner(&self, item_id: &ItemId, write_mode: bool) -> Result<File> {
+    async fn open_inner(&self, item_id: &ItemId, ext: &str, write_mode: bool) -> Result<File> {
         let meta = if let Some(cache) = &self.disk_cache {
+            let mut fetched = None;
             if let Some(state) = cache.get(item_id) {
-                log::debug!("File already cached: {:?}", item_id);
-                return Ok(File::Cached(state));
+                let (evict, revalidate) = match state.state.lock().await.status {
+                    FileCacheStatus::DownloadFailed => {
+                        (self.config.download.fail_on_incomplete, false)
+                    }
+                    // Invalidated by the integrity check.
+                    FileCacheStatus::Invalidated => (true, false),
+                    // Never revalidate dirty files, or local changes would be lost.
+                    FileCacheStatus::Available => (
+                        false,
+                        self.config.disk_cache.revalidate_on_open
+                            || state.unconfirmed.load(Ordering::Relaxed),
+                    ),
+                    _ => (false, false),
+                };
+                // Attributes are never fetched but synced, so this is the only metadata request.
+                // It's not made conditional with `If-None-Match`, which compares eTags that also
+                // change on renames, and the download URL is needed anyway if it's outdated.
+                if revalidate {
+                    let meta = Self::fetch_meta(
+                        item_id,
+                        &self.onedrive,
+                        &self.config.download,
+                        &self.event_tx,
+                    )
+                    .await?;
+                    let old_c_tag = state.c_tag.lock().unwrap().clone();
+                    if meta.c_tag == old_c_tag
+                        || !matches!(state.state.lock().await.status, FileCacheStatus::Available)
+                    {
+                        log::debug!("File already cached and revalidated: {:?}", item_id);
+                        state.unconfirmed.store(false, Ordering::Relaxed);
+                        cache.record_open(ext, true);
+                        return Ok(File::Cached(state));
+                    }
+                    log::info!(
+                        "Cache of {:?} is outdated on open, ctag: {:?} -> {:?}",
+                        item_id,
+                        old_c_tag,
+                        meta.c_tag,
+                    );
+                    cache.remove(item_id, &state);
+                    fetched = Some(meta);
+                } else if evict {
+                    log::info!(
+                        "Evicting failed or invalidated cache of {:?} for retry",
+                        item_id
+                    );
+                    cache.remove(item_id, &state);
+                } else {
+                    log::debug!("File already cached: {:?}", item_id);
+                    cache.record_open(ext, true);
+                    return Ok(File::Cached(state));
+                }
             }
+            cache.record_open(ext, false);
 
-            let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await).await?;
+            let meta = match fetched {
+                Some(meta) => meta,
+                None => {
+                    Self::fetch_meta(
+                        item_id,
+                        &self.onedrive,
+                        &self.config.download,
+                        &self.event_tx,
+                    )
+                    .await?
+                }
+            };
             if let Some(state) = cache.try_alloc_and_fetch(
                 item_id,
                 &meta,
@@ -162,21 +648,135 @@ impl FilePool {
         } else if write_mode {
             return Err(Error::WriteWithoutCache);
         } else {
-            Self::fetch_meta(item_id, &*self.onedrive.get().await).await?
+            Self::fetch_meta(
+                item_id,
+                &self.onedrive,
+                &self.config.download,
+                &self.event_tx,
+            )
+            .await?
         };
 
         log::debug!("Streaming file {:?}, meta: {:?}", item_id, meta);
-        let state =
-            FileStreamState::fetch(&meta, self.client.clone(), self.config.download.clone());
+        let state = FileStreamState::fetch(
+            item_id,
+            &meta,
+            self.onedrive.clone(),
+            self.client.clone(),
+            self.config.download.clone(),
+            self.transfers.clone(),
+        );
         Ok(File::Streaming(Arc::new(Mutex::new(state))))
     }
 
-    pub async fn open(&self, item_id: &ItemId, write_mode: bool) -> Result<u64> {
-        let file = self.open_inner(item_id, write_mode).await?;
-        let key = self.handles.insert(file).expect("Pool is full");
-        Ok(Self::key_to_fh(key))
+    /// Open a file. Files opened in `direct` mode are not read ahead, and are uploaded
+    /// without the flush delay after writes. `name` is only used to group stats.
+    pub async fn open(
+        &self,
+        item_id: &ItemId,
+        name: Option<&str>,
+        write_mode: bool,
+        direct: bool,
+    ) -> Result<u64> {
+        let ext = extension_group(name);
+        let file = self.open_inner(item_id, &ext, write_mode).await?;
+        if let File::Cached(file) = &file {
+            *file.extension.lock().unwrap() = Some(ext);
+        }
+        let key = self
+            .handles
+            .insert(Handle { file, direct })
+            .expect("Pool is full");
+        let fh = Self::key_to_fh(key);
+        self.open_items.lock().unwrap().insert(fh, item_id.clone());
+        Ok(fh)
+    }
+
+    /// Item ids of all open handles, sorted. Files opened multiple times appear multiple times.
+    pub fn open_files(&self) -> Vec<ItemId> {
+        let mut ret = self
+            .open_items
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        ret.sort_by(|a, b| a.0.cmp(&b.0));
+        ret
+    }
+
+    /// Max size of files to prefetch when their directory is listed. Zero if disabled.
+    pub fn warm_small_on_list(&self) -> u64 {
+        match &self.disk_cache {
+            Some(_) => self.config.disk_cache.warm_small_on_list,
+            None => 0,
+        }
+    }
+
+    /// Max number of upcoming files to prefetch on sequential access. Zero if disabled.
+    pub fn predictive_prefetch(&self) -> usize {
+        match &self.disk_cache {
+            Some(_) => self.config.disk_cache.predictive_prefetch,
+            None => 0,
+        }
+    }
+
+    /// Warm files into disk cache in background, only if they fit without evicting others.
+    pub fn prefetch(&self, item_ids: Vec<ItemId>) {
+        let cache = match &self.disk_cache {
+            Some(cache) => cache.clone(),
+            None => return,
+        };
+        let onedrive = self.onedrive.clone();
+        let event_tx = self.event_tx.clone();
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            for item_id in item_ids {
+                if cache.cache.lock().unwrap().contains_key(&item_id) {
+                    continue;
+                }
+                let meta =
+                    match Self::fetch_meta(&item_id, &onedrive, &cache.config.download, &event_tx)
+                        .await
+                    {
+                        Ok(meta) => meta,
+                        Err(err) => {
+                            log::debug!(
+                                "Failed to fetch metadata for prefetch {:?}: {}",
+                                item_id,
+                                err
+                            );
+                            continue;
+                        }
+                    };
+                if cache.config.disk_cache.max_cached_file_size < meta.size {
+                    continue;
+                }
+                if !cache.has_room_for(meta.size) {
+                    log::debug!("No room to prefetch {:?}, stopped", item_id);
+                    return;
+                }
+                match cache.try_alloc_and_fetch(
+                    &item_id,
+                    &meta,
+                    None,
+                    onedrive.clone(),
+                    event_tx.clone(),
+                    client.clone(),
+                ) {
+                    Ok(Some(_)) => log::debug!("Prefetching {:?}, meta: {:?}", item_id, meta),
+                    Ok(None) => {}
+                    Err(err) => log::warn!("Failed to prefetch {:?}: {}", item_id, err),
+                }
+            }
+        });
     }
 
+    /// Create or truncate a file and open it for writing.
+    ///
+    /// The empty file is uploaded right away even if content follows, since items, inodes and
+    /// caches are all keyed by the `ItemId` assigned by the server. Content written later is
+    /// uploaded once after `flush_delay`.
     pub async fn open_create_empty(
         &self,
         item_loc: ItemLocation<'_>,
@@ -190,7 +790,7 @@ impl FilePool {
             .upload_small(item_loc, Vec::new())
             .await?;
         assert_eq!(item.size, Some(0));
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attrs");
+        let attr = InodeAttr::parse_item(&item)?;
         let id = item.id.expect("Missing id");
         log::debug!("Truncated or created file {:?}", id);
 
@@ -199,9 +799,14 @@ impl FilePool {
             .await?;
         let key = self
             .handles
-            .insert(File::Cached(file))
+            .insert(Handle {
+                file: File::Cached(file),
+                direct: false,
+            })
             .expect("Pool is full");
-        Ok((Self::key_to_fh(key), id, attr))
+        let fh = Self::key_to_fh(key);
+        self.open_items.lock().unwrap().insert(fh, id.clone());
+        Ok((fh, id, attr))
     }
 
     pub async fn truncate_file(
@@ -210,7 +815,8 @@ impl FilePool {
         new_size: u64,
         mtime: SystemTime,
     ) -> Result<()> {
-        if new_size > self.config.disk_cache.max_cached_file_size {
+        // Truncation always makes the file dirty, so it's limited the same as writes.
+        if new_size > self.config.upload.max_size {
             return Err(Error::FileTooLarge);
         }
 
@@ -222,10 +828,15 @@ impl FilePool {
             match guard.status {
                 FileCacheStatus::Downloading { truncate } => {
                     let download_size = truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size);
-                    guard.status = FileCacheStatus::Downloading {
-                        truncate: Some((download_size.min(new_size), mtime)),
-                    };
-                    guard.file_size = new_size;
+                    file.set_status(
+                        &mut guard,
+                        FileCacheStatus::Downloading {
+                            truncate: Some((download_size.min(new_size), mtime)),
+                        },
+                    );
+                    // It's uploaded after the download, which must not pause.
+                    guard.want_all();
+                    file.set_file_size(&mut guard, new_size);
                     guard.cache_file.set_len(new_size).await.unwrap();
                     log::debug!(
                         "Pending another truncate for still downloading file {:?}",
@@ -240,8 +851,7 @@ impl FilePool {
                         guard.file_size,
                         new_size,
                     );
-                    guard.file_size = new_size;
-                    guard.cache_file.set_len(new_size).await.unwrap();
+                    // Leave `Available` state before modifying the content.
                     file.queue_upload(
                         &mut guard,
                         mtime,
@@ -250,13 +860,21 @@ impl FilePool {
                         self.event_tx.clone(),
                         self.config.upload.clone(),
                     );
+                    file.set_file_size(&mut guard, new_size);
+                    guard.cache_file.set_len(new_size).await.unwrap();
                     return Ok(());
                 }
                 FileCacheStatus::DownloadFailed | FileCacheStatus::Invalidated => {}
             }
         }
 
-        let meta = Self::fetch_meta(item_id, &*self.onedrive.get().await).await?;
+        let meta = Self::fetch_meta(
+            item_id,
+            &self.onedrive,
+            &self.config.download,
+            &self.event_tx,
+        )
+        .await?;
         log::debug!(
             "Download with truncate {:?}: new size: {}, remote meta: {:?}",
             item_id,
@@ -279,35 +897,63 @@ impl FilePool {
 
     pub async fn close(&self, fh: u64) -> Result<()> {
         if self.handles.remove(Self::fh_to_key(fh)) {
+            self.open_items.lock().unwrap().remove(&fh);
             Ok(())
         } else {
             Err(Error::InvalidHandle(fh))
         }
     }
 
-    pub async fn read(&self, fh: u64, offset: u64, size: usize) -> Result<impl AsRef<[u8]>> {
-        let file = self
+    pub async fn read(&self, fh: u64, offset: u64, size: usize) -> Result<Bytes> {
+        let (file, direct) = {
+            let handle = self
+                .handles
+                .get(Self::fh_to_key(fh))
+                .ok_or(Error::InvalidHandle(fh))?;
+            (handle.file.clone(), handle.direct)
+        };
+        match file {
+            File::Streaming(state) => {
+                let mut state = state.lock().await;
+                let ret = state.read(offset, size).await;
+                ret.map_err(|err| self.transfers.explain(err, &state.item_id))
+            }
+            File::Cached(state) => {
+                let reuse_buf = self.config.disk_cache.reuse_read_buffer;
+                // Short reads are taken as EOF by the page cache, so only direct reads may do.
+                let short_read = direct && self.config.disk_cache.short_read;
+                FileCache::read(&state, offset, size, reuse_buf, !direct, short_read)
+                    .await
+                    .map_err(|err| self.transfers.explain(err, &state.item_id))
+            }
+        }
+    }
+
+    /// Fail if the handle is not backed by cache, which is required for modifications.
+    pub fn ensure_cached(&self, fh: u64) -> Result<()> {
+        let handle = self
             .handles
             .get(Self::fh_to_key(fh))
-            .ok_or(Error::InvalidHandle(fh))?
-            .clone();
-        match file {
-            File::Streaming(state) => state.lock().await.read(offset, size).await,
-            File::Cached(state) => FileCache::read(&state, offset, size).await,
+            .ok_or(Error::InvalidHandle(fh))?;
+        match handle.file {
+            File::Streaming(_) => Err(Error::WriteWithoutCache),
+            File::Cached(_) => Ok(()),
         }
     }
 
     /// Write to cached file. Returns item id and file size after the write.
     pub async fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<UpdatedFileAttr> {
-        let file = self
-            .handles
-            .get(Self::fh_to_key(fh))
-            .ok_or(Error::InvalidHandle(fh))?
-            .clone();
+        let (file, direct) = {
+            let handle = self
+                .handles
+                .get(Self::fh_to_key(fh))
+                .ok_or(Error::InvalidHandle(fh))?;
+            (handle.file.clone(), handle.direct)
+        };
         match file {
             File::Streaming { .. } => panic!("Cannot stream in write mode"),
             File::Cached(state) => {
-                FileCache::write(
+                let attr = FileCache::write(
                     &state,
                     offset,
                     data,
@@ -316,7 +962,11 @@ impl FilePool {
                     self.client.clone(),
                     self.config.upload.clone(),
                 )
-                .await
+                .await?;
+                if direct {
+                    state.flush_now().await;
+                }
+                Ok(attr)
             }
         }
     }
@@ -328,7 +978,10 @@ impl FilePool {
                 match guard.status {
                     FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
                     FileCacheStatus::Available | FileCacheStatus::Invalidated => return Ok(()),
-                    FileCacheStatus::Downloading { .. } => {
+                    // Nothing to upload for a plain download. Don't wait for it to finish.
+                    FileCacheStatus::Downloading { truncate: None } => return Ok(()),
+                    // A pending truncation will be uploaded after the download.
+                    FileCacheStatus::Downloading { truncate: Some(_) } => {
                         let mut rx = guard.available_size.clone();
                         drop(guard);
                         while rx.changed().await.is_ok() {}
@@ -349,7 +1002,15 @@ impl FilePool {
                     if let Some(flush_tx) = flush_tx {
                         let _ = flush_tx.send(());
                     }
-                    while done_rx.changed().await.is_ok() {}
+                    // The upload is kept to be retried once space frees up, but waiting for it
+                    // may take forever.
+                    let mut quota_rx = self.transfers.quota_exceeded.subscribe();
+                    tokio::select! {
+                        _ = async { while done_rx.changed().await.is_ok() {} } => {}
+                        _ = quota_rx.wait_for(|exceeded| *exceeded) => {
+                            return Err(Error::QuotaExceeded);
+                        }
+                    }
                     // May be canceled by another modification during the upload.
                     if *done_rx.borrow() {
                         return Ok(());
@@ -361,11 +1022,163 @@ impl FilePool {
         Ok(())
     }
 
+    /// Flush files one by one in the given order. Each upload starts only after the previous one
+    /// completes, eg. an index file is uploaded after all data files it references.
+    pub async fn flush_ordered(&self, item_ids: &[ItemId]) -> Result<()> {
+        for item_id in item_ids {
+            self.flush_file(item_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the file is fully downloaded into cache.
+    /// Returns `false` immediately if it's not in cache.
+    pub async fn await_cached(&self, item_id: &ItemId) -> Result<bool> {
+        let file = match self
+            .disk_cache
+            .as_ref()
+            .and_then(|cache| cache.get(item_id))
+        {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        loop {
+            let guard = file.state.lock().await;
+            match guard.status {
+                FileCacheStatus::Downloading { .. } => {
+                    guard.want_all();
+                    let mut rx = guard.available_size.clone();
+                    drop(guard);
+                    while rx.changed().await.is_ok() {}
+                }
+                FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+                FileCacheStatus::Invalidated => return Ok(false),
+                FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => return Ok(true),
+            }
+        }
+    }
+
+    /// Whether `verify_consistency` should be run after each sync.
+    pub fn check_consistency(&self) -> bool {
+        self.disk_cache.is_some() && self.config.disk_cache.check_consistency
+    }
+
+    /// Check sizes of cache entries agree with their cache files and the budget, and with
+    /// `remote_size` for files synchronized with remote side. Returns the inconsistencies found.
+    pub async fn verify_consistency(
+        &self,
+        remote_size: impl Fn(&ItemId) -> Option<u64>,
+    ) -> Vec<String> {
+        let cache = match &self.disk_cache {
+            Some(cache) => cache,
+            None => return Vec::new(),
+        };
+        let files = cache
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, file)| file.clone())
+            .collect::<Vec<_>>();
+        let mut ret = Vec::new();
+        let mut total_size = 0;
+        for file in files {
+            let guard = file.state.lock().await;
+            total_size += guard.file_size;
+            let id = &file.item_id;
+
+            let is_available = matches!(guard.status, FileCacheStatus::Available);
+            let available_file_size = file.available_file_size.load(Ordering::Acquire);
+            let expect = if is_available {
+                guard.file_size
+            } else {
+                FileCache::NOT_AVAILABLE
+            };
+            if available_file_size != expect {
+                ret.push(format!(
+                    "{:?} has available size {} but expecting {}",
+                    id, available_file_size, expect,
+                ));
+            }
+
+            // Content of failed files is not used anymore.
+            if !matches!(
+                guard.status,
+                FileCacheStatus::Invalidated | FileCacheStatus::DownloadFailed
+            ) {
+                match file.raw_file.metadata() {
+                    Ok(meta) if meta.len() != guard.file_size => ret.push(format!(
+                        "{:?} has cache file of {} B but file size {} B",
+                        id,
+                        meta.len(),
+                        guard.file_size,
+                    )),
+                    Ok(_) => {}
+                    Err(err) => ret.push(format!("Failed to stat cache file of {:?}: {}", id, err)),
+                }
+            }
+
+            if is_available {
+                if let Some(size) = remote_size(id).filter(|&size| size != guard.file_size) {
+                    ret.push(format!(
+                        "{:?} has file size {} B but {} B on remote side",
+                        id, guard.file_size, size,
+                    ));
+                }
+            }
+        }
+        // Evicted files still opened are counted as well.
+        let counted = cache.total_size.load(Ordering::Acquire);
+        if counted < total_size {
+            ret.push(format!(
+                "Total size counts {} B but cached files have {} B",
+                counted, total_size,
+            ));
+        }
+        ret
+    }
+
+    /// Immediately retry all uploads waiting for retry, since the network is restored.
+    pub fn notify_reconnected(&self) {
+        log::info!("Network restored, retrying pending uploads");
+        self.transfers.reconnected.notify_waiters();
+    }
+
+    /// Resume uploads paused by `507 Insufficient Storage`, since some space is freed.
+    pub fn notify_quota_freed(&self) {
+        if self.transfers.quota_exceeded.send_replace(false) {
+            log::info!("Drive space freed, resuming paused uploads");
+        }
+    }
+
     pub async fn sync_items(&self, items: &[DriveItem]) {
         if let Some(cache) = &self.disk_cache {
             cache.sync_items(items).await;
         }
     }
+
+    /// Disk cache usage grouped by file extensions, the largest first. Files never opened, eg.
+    /// prefetched ones, are grouped as unknown.
+    pub fn stats_by_extension(&self) -> Vec<(String, ExtensionStats)> {
+        match &self.disk_cache {
+            Some(cache) => cache.stats_by_extension(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drop the cache of a file removed locally. Open handles of it fail afterwards.
+    pub async fn remove(&self, item_id: &ItemId) {
+        if let Some(cache) = &self.disk_cache {
+            cache.invalidate(item_id).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Handle {
+    file: File,
+    /// Opened with `O_DIRECT`.
+    direct: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -377,9 +1190,37 @@ enum File {
 #[derive(Debug)]
 struct FileStreamState {
     file_size: u64,
+    /// Downloads at different positions, the most recently used first. Readers interleaving at
+    /// distant positions of the same handle each get one, instead of restarting a single one.
+    cursors: Vec<StreamCursor>,
+    /// Set when the download failed and `fail_on_incomplete` is set. All further reads fail.
+    failed: bool,
+    // For restarting the download on seeks.
+    item_id: ItemId,
+    source: Arc<DownloadSource>,
+    client: reqwest::Client,
+    config: DownloadConfig,
+    transfers: Arc<TransferTracker>,
+}
+
+/// A sequential download of a streaming file, with its ring buffer.
+#[derive(Debug)]
+struct StreamCursor {
     buf_start_pos: u64,
     buf: RingBuf,
-    rx: mpsc::Receiver<Bytes>,
+    rx: mpsc::Receiver<StreamChunk>,
+}
+
+/// A chunk buffered for a streaming download, holding its share of `max_stream_buffer_bytes`.
+type StreamChunk = (u64, Bytes, Option<OwnedSemaphorePermit>);
+
+impl StreamCursor {
+    /// Whether a read at `offset` can be served without restarting the download, ie. it's in the
+    /// buffer or shortly ahead of it.
+    fn covers(&self, offset: u64) -> bool {
+        let buf_end = self.buf_start_pos + self.buf.len() as u64;
+        self.buf_start_pos <= offset && offset <= buf_end + self.buf.capacity() as u64
+    }
 }
 
 #[derive(Debug)]
@@ -399,6 +1240,11 @@ impl RingBuf {
         self.v.len() - 1
     }
 
+    fn clear(&mut self) {
+        self.l = 0;
+        self.r = 0;
+    }
+
     fn len(&self) -> usize {
         if self.l <= self.r {
             self.r - self.l
@@ -443,50 +1289,160 @@ impl RingBuf {
 }
 
 impl FileStreamState {
-    fn fetch(meta: &RemoteFileMeta, client: reqwest::Client, config: DownloadConfig) -> Self {
-        let (tx, rx) = mpsc::channel(config.stream_buffer_chunks);
-        let buf = RingBuf::new(config.stream_ring_buffer_size);
-        tokio::spawn(download_thread(
-            meta.size,
-            meta.download_url.clone(),
-            tx,
+    fn fetch(
+        item_id: &ItemId,
+        meta: &RemoteFileMeta,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        config: DownloadConfig,
+        transfers: Arc<TransferTracker>,
+    ) -> Self {
+        let mut this = Self {
+            file_size: meta.size,
+            cursors: Vec::new(),
+            failed: false,
+            item_id: item_id.clone(),
+            source: DownloadSource::new(item_id, meta, onedrive),
             client,
             config,
-        ));
-        Self {
-            file_size: meta.size,
-            buf_start_pos: 0,
-            buf,
-            rx,
+            transfers,
+        };
+        // Otherwise, the first read starts the download from its offset in `seek`.
+        if !this.config.lazy_stream_start {
+            let rx = this.download_from(0);
+            this.cursors.push(StreamCursor {
+                buf_start_pos: 0,
+                buf: RingBuf::new(this.config.stream_ring_buffer_size),
+                rx,
+            });
         }
+        this
     }
 
-    async fn read(&mut self, offset: u64, size: usize) -> Result<Bytes> {
-        let size = (self.file_size.saturating_sub(offset)).min(size as u64) as usize;
+    /// Start a download from `pos`.
+    /// It runs ahead of reads by up to `stream_buffer_chunks` chunks queued in the returned
+    /// channel, bounded by `max_stream_buffer_bytes` in total, so reads of queued data never wait
+    /// for the network. It stops once it finds the channel closed.
+    fn download_from(&self, pos: u64) -> mpsc::Receiver<StreamChunk> {
+        let (tx, rx) = mpsc::channel(self.config.stream_buffer_chunks);
+        // Hold at most one chunk outside of the budget.
+        let (chunk_tx, mut chunk_rx) = mpsc::channel(1);
+        tokio::spawn(download_thread(
+            pos,
+            self.file_size,
+            self.source.clone(),
+            chunk_tx,
+            self.client.clone(),
+            self.config.clone(),
+            Arc::new(self.transfers.start(TransferKind::Download, &self.item_id)),
+        ));
+        let budget = self.transfers.stream_buffer_budget.clone();
+        let max_bytes = self.config.max_stream_buffer_bytes;
+        tokio::spawn(async move {
+            while let Some((chunk_pos, chunk)) = chunk_rx.recv().await {
+                let permit = match &budget {
+                    Some(budget) => {
+                        // A chunk larger than the whole budget takes all of it.
+                        let bytes = chunk.len().min(max_bytes) as u32;
+                        tokio::select! {
+                            permit = budget.clone().acquire_many_owned(bytes) => {
+                                Some(permit.expect("Never closed"))
+                            }
+                            // Stop waiting if the cursor is restarted or the file is closed.
+                            () = tx.closed() => return,
+                        }
+                    }
+                    None => None,
+                };
+                if tx.send((chunk_pos, chunk, permit)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+
+    /// Move the cursor covering `offset` to the front, or start a new one from `offset` there.
+    /// Once there are `stream_cursors` of them, the least recently used one is restarted,
+    /// dropping its buffered data.
+    fn seek(&mut self, offset: u64) {
+        if let Some(idx) = self.cursors.iter().position(|c| c.covers(offset)) {
+            let cursor = self.cursors.remove(idx);
+            self.cursors.insert(0, cursor);
+            return;
+        }
+        log::debug!(
+            "Seek streaming {:?} to {}, with {} downloads at {:?}",
+            self.item_id,
+            offset,
+            self.cursors.len(),
+            self.cursors
+                .iter()
+                .map(|c| c.buf_start_pos)
+                .collect::<Vec<_>>(),
+        );
+        let rx = self.download_from(offset);
+        let cursor = if self.cursors.len() < self.config.stream_cursors {
+            StreamCursor {
+                buf_start_pos: offset,
+                buf: RingBuf::new(self.config.stream_ring_buffer_size),
+                rx,
+            }
+        } else {
+            // Reuse the buffer. The previous download stops as its channel is dropped here.
+            let mut cursor = self.cursors.pop().expect("At least one cursor");
+            cursor.buf.clear();
+            cursor.buf_start_pos = offset;
+            cursor.rx = rx;
+            cursor
+        };
+        self.cursors.insert(0, cursor);
+    }
+
+    async fn read(&mut self, offset: u64, size: usize) -> Result<Bytes> {
+        if self.failed {
+            return Err(Error::DownloadFailed);
+        }
+        let size = (self.file_size.saturating_sub(offset)).min(size as u64) as usize;
         if size == 0 {
             return Ok(Bytes::new());
         }
         let end = offset + size as u64;
 
-        while self.buf_start_pos + (self.buf.len() as u64) < end {
-            let chunk = match self.rx.recv().await {
-                Some(chunk) => chunk,
-                None => return Err(Error::DownloadFailed),
+        // Seek by restarting a download, if the data before `offset` is already dropped, or is
+        // too far ahead to be worth streaming through. Small seeks within the buffer, or shortly
+        // ahead of it, reuse the current connection.
+        self.seek(offset);
+        let cursor = &mut self.cursors[0];
+
+        while cursor.buf_start_pos + (cursor.buf.len() as u64) < end {
+            let chunk = match cursor.rx.recv().await {
+                // The budget is released once it's moved into the ring buffer.
+                Some((chunk_pos, chunk, _permit)) => {
+                    // Chunks of previous downloads are dropped along with their channels.
+                    debug_assert_eq!(chunk_pos, cursor.buf_start_pos + cursor.buf.len() as u64);
+                    chunk
+                }
+                None => {
+                    self.failed = self.config.fail_on_incomplete;
+                    return Err(Error::DownloadFailed);
+                }
             };
-            let advance = self.buf.feed(&chunk);
-            self.buf_start_pos += advance as u64;
+            let advance = cursor.buf.feed(&chunk);
+            cursor.buf_start_pos += advance as u64;
         }
 
-        if offset < self.buf_start_pos {
+        // Seeks are handled above. This only happens if the read is larger than the ring buffer.
+        if offset < cursor.buf_start_pos {
             return Err(Error::NonsequentialRead {
-                current_pos: self.buf_start_pos,
+                current_pos: cursor.buf_start_pos,
                 read_offset: offset,
                 read_size: size,
             });
         }
 
-        let start = (offset - self.buf_start_pos) as usize;
-        let (lhs, rhs) = self.buf.slice(start..(start + size));
+        let start = (offset - cursor.buf_start_pos) as usize;
+        let (lhs, rhs) = cursor.buf.slice(start..(start + size));
         let mut ret = BytesMut::with_capacity(size);
         ret.extend_from_slice(lhs);
         ret.extend_from_slice(rhs);
@@ -494,49 +1450,109 @@ impl FileStreamState {
     }
 }
 
+/// Download the range `pos..end` of the file, and send chunks with their offsets.
+/// The transfer may be shared by downloads of different ranges of the same file.
+/// Returns whether the whole range is downloaded.
 async fn download_thread(
-    file_size: u64,
-    download_url: String,
-    tx: mpsc::Sender<Bytes>,
+    mut pos: u64,
+    end: u64,
+    source: Arc<DownloadSource>,
+    tx: mpsc::Sender<(u64, Bytes)>,
     client: reqwest::Client,
     config: DownloadConfig,
-) {
-    let mut pos = 0u64;
+    transfer: Arc<TransferGuard>,
+) -> bool {
+    const MAX_THROTTLE_RETRY: u32 = 10;
 
-    log::debug!("Start downloading ({} bytes)", file_size);
+    log::debug!("Start downloading range {}..{}", pos, end);
 
-    while pos < file_size {
+    while pos < end {
         let mut tries = 0;
+        let mut throttled = 0;
         let mut resp = loop {
-            let ret: anyhow::Result<_> = client
+            let download_url = source.url().await;
+            let ret = client
                 .get(&download_url)
                 // We already have timeout for each chunk.
                 // FIXME: Use `Duration::MAX`.
                 .timeout(Duration::from_secs(u64::MAX))
-                .header(header::RANGE, format!("bytes={}-", pos))
+                .header(header::RANGE, format!("bytes={}-{}", pos, end - 1))
                 .send()
-                .await
-                .map_err(|err| err.into())
-                .and_then(|resp| {
-                    if resp.status() != StatusCode::PARTIAL_CONTENT {
-                        anyhow::bail!("Not Partial Content response: {}", resp.status());
+                .await;
+            // Throttling is not a failure, and is not counted in `max_retry`, unless it lasts
+            // longer than `MAX_THROTTLE_RETRY` in a row.
+            if let Ok(resp) = &ret {
+                let status = resp.status();
+                if (status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::SERVICE_UNAVAILABLE)
+                    && throttled < MAX_THROTTLE_RETRY
+                {
+                    let delay = retry_after(resp.headers()).unwrap_or(config.retry_delay);
+                    throttled += 1;
+                    log::warn!(
+                        "Download throttled ({}), retry {}/{} in {:?}",
+                        status,
+                        throttled,
+                        MAX_THROTTLE_RETRY,
+                        delay,
+                    );
+                    // Stop waiting if the consumer is gone, eg. the handle is closed.
+                    if tx.is_closed() {
+                        return false;
                     }
-                    Ok(resp)
-                });
+                    tokio::time::sleep(delay).await;
+                    if tx.is_closed() {
+                        return false;
+                    }
+                    continue;
+                }
+            }
+            throttled = 0;
+            let status = match &ret {
+                Ok(resp) => Some(resp.status()),
+                Err(err) => err.status(),
+            };
+            let ret: anyhow::Result<_> = ret.map_err(|err| err.into()).and_then(|resp| {
+                if resp.status() != StatusCode::PARTIAL_CONTENT {
+                    anyhow::bail!("Not Partial Content response: {}", resp.status());
+                }
+                // Download URLs may redirect to other CDN hosts. Make sure the range is
+                // still respected, or the content would be corrupted.
+                let start = content_range_start(resp.headers());
+                if start != Some(pos) {
+                    anyhow::bail!(
+                        "Unexpected Content-Range from {}: {:?}, expecting start at {}",
+                        resp.url().host_str().unwrap_or_default(),
+                        resp.headers().get(header::CONTENT_RANGE),
+                        pos,
+                    );
+                }
+                Ok(resp)
+            });
             match ret {
                 Ok(resp) => break resp,
                 Err(err) => {
                     tries += 1;
-                    log::error!(
-                        "Error downloading file (try {}/{}): {}",
-                        tries,
-                        config.max_retry,
-                        err,
+                    let retryable = tries <= config.max_retry;
+                    transfer.report_error(
+                        status,
+                        tries as u32,
+                        retryable,
+                        format_args!("range {}..{}: {:#}", pos, end, err),
                     );
-                    if config.max_retry < tries {
-                        return;
+                    if !retryable {
+                        return false;
+                    }
+                    if matches!(
+                        status,
+                        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+                    ) {
+                        if !source.refresh(&download_url).await {
+                            return false;
+                        }
+                    } else {
+                        tokio::time::sleep(config.retry_delay).await;
                     }
-                    tokio::time::sleep(config.retry_delay).await;
                 }
             }
         };
@@ -552,7 +1568,7 @@ async fn download_thread(
                     break;
                 }
                 Ok(Ok(None)) => {
-                    if pos != file_size {
+                    if pos != end {
                         log::error!("Download stream ends too early");
                     }
                     break;
@@ -560,50 +1576,988 @@ async fn download_thread(
                 Ok(Ok(Some(chunk))) => chunk,
             };
 
+            // Paced before handing out, so that the average rate is limited. Readers waiting for
+            // the data are notified by the writer, which is never blocked by the limiter.
+            // The limiter is global, but holds no lock while waiting, so a consumer that stops
+            // reading only stalls this download on `tx`, not others.
+            if let Some(limiter) = &transfer.tracker.download_limiter {
+                limiter.acquire(chunk.len() as u64).await;
+            }
+
+            let chunk_pos = pos;
             pos += chunk.len() as u64;
-            assert!(pos <= file_size);
-            if tx.send(chunk).await.is_err() {
-                log::debug!(
-                    "Download stopped at {} bytes ({} bytes in total)",
-                    pos,
-                    file_size,
-                );
+            transfer.add_bytes(chunk.len() as u64);
+            assert!(pos <= end);
+            if tx.send((chunk_pos, chunk)).await.is_err() {
+                log::debug!("Download stopped at {} (range end at {})", pos, end);
+                return false;
+            }
+        }
+    }
+
+    assert_eq!(pos, end);
+    log::debug!("Download finished (range end at {})", end);
+    true
+}
+
+/// Download segments taken from `queue` one by one, until it's empty or any download fails.
+async fn download_worker_thread(
+    queue: Arc<SyncMutex<VecDeque<Range<u64>>>>,
+    source: Arc<DownloadSource>,
+    tx: mpsc::Sender<(u64, Bytes)>,
+    client: reqwest::Client,
+    config: DownloadConfig,
+    transfer: Arc<TransferGuard>,
+) {
+    loop {
+        let seg = match queue.lock().unwrap().pop_front() {
+            Some(seg) => seg,
+            None => return,
+        };
+        let done = download_thread(
+            seg.start,
+            seg.end,
+            source.clone(),
+            tx.clone(),
+            client.clone(),
+            config.clone(),
+            transfer.clone(),
+        )
+        .await;
+        if !done {
+            // The file fails anyway. Stop other workers.
+            queue.lock().unwrap().clear();
+            return;
+        }
+    }
+}
+
+/// Split `0..size` into contiguous segments of `seg_len`, except for the last one.
+fn split_segments(size: u64, seg_len: u64) -> Vec<Range<u64>> {
+    let seg_len = seg_len.max(1);
+    (0..size)
+        .step_by(seg_len as usize)
+        .map(|start| start..(start + seg_len).min(size))
+        .collect()
+}
+
+/// Shared states of downloads and uploads.
+#[derive(Debug)]
+struct TransferTracker {
+    running: AtomicUsize,
+    /// Number of cached files in `Dirty` status.
+    dirty_files: AtomicUsize,
+    /// Whether there are any running transfers.
+    syncing_tx: watch::Sender<bool>,
+    /// Global limiter for all uploads.
+    upload_limiter: Option<RateLimiter>,
+    /// Global limiter for all downloads.
+    download_limiter: Option<RateLimiter>,
+    /// Part buffers shared by all uploads.
+    upload_buffers: BufferPool,
+    /// Bytes of chunks all streaming downloads may buffer ahead of reads, or `None` if unlimited.
+    stream_buffer_budget: Option<Arc<Semaphore>>,
+    /// Whether to check cache files are not modified externally before reads and uploads.
+    verify_cache_file: bool,
+    /// Whether to check downloaded cache files against the remote QuickXorHash.
+    verify_hash: bool,
+    /// Where upload sessions are persisted, or `None` if they are not resumed after restarts.
+    upload_sidecars: Option<UploadSidecars>,
+    /// Number of concurrent read-ahead blocks for sequential reads on available cache files.
+    read_ahead_workers: usize,
+    /// How far cache downloads run ahead of the highest read, or zero for no limit.
+    prefetch_bytes: u64,
+    /// Notified when the network is restored, to wake up all uploads waiting for retry.
+    reconnected: Notify,
+    /// Whether uploads failed with `507 Insufficient Storage`. Uploads are paused until it's reset.
+    quota_exceeded: watch::Sender<bool>,
+    /// The latest failure of recently failed items.
+    errors: SyncMutex<LruCache<ItemId, TransferError>>,
+    /// The latest failure of all transfers.
+    last_error: SyncMutex<Option<TransferError>>,
+    /// Running transfers by their ids.
+    active: SyncMutex<HashMap<u64, Arc<TransferStat>>>,
+    next_id: AtomicU64,
+}
+
+impl TransferTracker {
+    /// Number of failed items to remember.
+    const MAX_ERRORS: usize = 64;
+
+    fn new(config: &Config) -> Arc<Self> {
+        Arc::new(Self {
+            running: 0.into(),
+            dirty_files: 0.into(),
+            syncing_tx: watch::channel(false).0,
+            upload_limiter: RateLimiter::new(config.upload.max_bytes_per_sec),
+            download_limiter: RateLimiter::new(config.download.max_bytes_per_sec),
+            upload_buffers: BufferPool::new(config.upload.max_part_buffers),
+            stream_buffer_budget: (config.download.max_stream_buffer_bytes != 0).then(|| {
+                let max_bytes = config.download.max_stream_buffer_bytes;
+                Arc::new(Semaphore::new(max_bytes.min(Semaphore::MAX_PERMITS)))
+            }),
+            verify_cache_file: config.disk_cache.verify_cache_file,
+            verify_hash: config.disk_cache.verify_hash,
+            // Anonymous memory files are lost on exit anyway.
+            upload_sidecars: (config.upload.resume_on_restart
+                && config.disk_cache.enable
+                && config.disk_cache.backend == BackendKind::Disk)
+                .then(|| UploadSidecars {
+                    dir: config.disk_cache.path.join(UploadSidecars::DIR_NAME),
+                }),
+            read_ahead_workers: config.disk_cache.read_ahead_workers,
+            prefetch_bytes: config.download.prefetch_bytes,
+            reconnected: Notify::new(),
+            quota_exceeded: watch::channel(false).0,
+            errors: SyncMutex::new(LruCache::new(Self::MAX_ERRORS)),
+            last_error: SyncMutex::new(None),
+            active: SyncMutex::new(HashMap::new()),
+            next_id: 0.into(),
+        })
+    }
+
+    fn list(&self) -> Vec<TransferInfo> {
+        let active = self.active.lock().unwrap();
+        let mut ret = active.values().map(|stat| stat.info()).collect::<Vec<_>>();
+        ret.sort_by(|a, b| (a.kind as u8, &a.item_id.0).cmp(&(b.kind as u8, &b.item_id.0)));
+        ret
+    }
+
+    /// Wait for the retry delay, or until the network is restored if `retry_on_reconnect` is set.
+    async fn retry_delay(&self, config: &UploadConfig) {
+        if config.retry_on_reconnect {
+            tokio::select! {
+                _ = time::sleep(config.retry_delay) => {}
+                _ = self.reconnected.notified() => {}
+            }
+        } else {
+            time::sleep(config.retry_delay).await;
+        }
+    }
+
+    fn report_error(&self, err: TransferError) {
+        log::error!("{}", err);
+        self.errors
+            .lock()
+            .unwrap()
+            .insert(err.item_id.clone(), err.clone());
+        *self.last_error.lock().unwrap() = Some(err);
+    }
+
+    /// Replace `DownloadFailed` with the failure reported for `item_id`, if any.
+    fn explain(&self, err: Error, item_id: &ItemId) -> Error {
+        match err {
+            Error::DownloadFailed => match self.errors.lock().unwrap().get_mut(item_id) {
+                Some(failure) => Error::Transfer(Box::new(failure.clone())),
+                None => err,
+            },
+            err => err,
+        }
+    }
+
+    /// Wait before retrying an upload failed with `err`. If the drive is full, it waits until
+    /// space frees up instead, since retries never succeed before that.
+    async fn upload_retry_delay(
+        &self,
+        err: &onedrive_api::Error,
+        config: &UploadConfig,
+        event_tx: &mpsc::Sender<UpdateEvent>,
+    ) {
+        if err.status_code() != Some(StatusCode::INSUFFICIENT_STORAGE) {
+            return self.retry_delay(config).await;
+        }
+        if !self.quota_exceeded.send_replace(true) {
+            log::error!("Drive is full, all uploads are paused until space frees up");
+            let _ = event_tx.send(UpdateEvent::QuotaExceeded).await;
+        }
+        let _ = self
+            .quota_exceeded
+            .subscribe()
+            .wait_for(|exceeded| !exceeded)
+            .await;
+    }
+
+    /// Register a running transfer, which is unregistered when the returned guard is dropped.
+    fn start(self: &Arc<Self>, kind: TransferKind, item_id: &ItemId) -> TransferGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stat = Arc::new(TransferStat {
+            kind,
+            item_id: item_id.clone(),
+            bytes: 0.into(),
+            window: SyncMutex::new(SpeedWindow {
+                start_time: Instant::now(),
+                start_bytes: 0,
+                speed: None,
+            }),
+            progress: SyncMutex::new(None),
+        });
+        self.active.lock().unwrap().insert(id, stat.clone());
+        self.running.fetch_add(1, Ordering::SeqCst);
+        self.notify();
+        TransferGuard {
+            tracker: self.clone(),
+            id,
+            stat,
+        }
+    }
+
+    fn notify(&self) {
+        // Re-read the counter inside the lock of the channel, so that racing transitions
+        // always settle to the latest state.
+        self.syncing_tx.send_if_modified(|syncing| {
+            let new = self.running.load(Ordering::SeqCst) != 0;
+            std::mem::replace(syncing, new) != new
+        });
+    }
+}
+
+/// A bounded pool of recycled buffers.
+#[derive(Debug)]
+struct BufferPool {
+    permits: Semaphore,
+    free: SyncMutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    fn new(max_buffers: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max_buffers),
+            free: SyncMutex::new(Vec::new()),
+        }
+    }
+
+    /// Wait for a free buffer of length `len`. It's put back to the pool when dropped.
+    async fn get(&self, len: usize) -> PooledBuf<'_> {
+        let permit = self.permits.acquire().await.expect("Never closed");
+        let mut buf = self.free.lock().unwrap().pop().unwrap_or_default();
+        // The memory is reclaimed here if all `Bytes` split from the last use are dropped.
+        buf.clear();
+        buf.resize(len, 0);
+        PooledBuf {
+            pool: self,
+            buf,
+            _permit: permit,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PooledBuf<'a> {
+    pool: &'a BufferPool,
+    buf: BytesMut,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Drop for PooledBuf<'_> {
+    fn drop(&mut self) {
+        let buf = std::mem::take(&mut self.buf);
+        self.pool.free.lock().unwrap().push(buf);
+    }
+}
+
+/// Throttle of `UpdateEvent::UploadProgress` of an upload, so that many small parts never flood
+/// `event_tx`. The first and the final progress are always sent, and it never goes backwards,
+/// eg. when the upload restarts.
+#[derive(Debug, Default)]
+struct ProgressThrottle {
+    last: Option<(Instant, u64)>,
+}
+
+impl ProgressThrottle {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Whether `uploaded` of `total` bytes at `now` should be sent.
+    fn should_send(&mut self, now: Instant, uploaded: u64, total: u64) -> bool {
+        let send = match self.last {
+            None => true,
+            Some((_, last)) if uploaded <= last => false,
+            Some(_) if uploaded == total => true,
+            Some((time, _)) => Self::INTERVAL <= now.saturating_duration_since(time),
+        };
+        if send {
+            self.last = Some((now, uploaded));
+        }
+        send
+    }
+}
+
+#[derive(Debug)]
+struct TransferGuard {
+    tracker: Arc<TransferTracker>,
+    id: u64,
+    stat: Arc<TransferStat>,
+}
+
+impl TransferGuard {
+    fn add_bytes(&self, len: u64) {
+        self.stat.add_bytes(len);
+    }
+
+    /// Report `done` of `total` bytes are committed. Retries may send bytes more than once,
+    /// so it's not derived from `add_bytes`.
+    fn set_progress(&self, done: u64, total: u64) {
+        *self.stat.progress.lock().unwrap() = Some((done, total));
+    }
+
+    fn report_error(
+        &self,
+        status: Option<StatusCode>,
+        attempt: u32,
+        retryable: bool,
+        message: impl fmt::Display,
+    ) {
+        self.tracker.report_error(TransferError {
+            kind: self.stat.kind,
+            item_id: self.stat.item_id.clone(),
+            name: None,
+            status,
+            attempt,
+            retryable,
+            message: message.to_string(),
+        });
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.tracker.active.lock().unwrap().remove(&self.id);
+        self.tracker.running.fetch_sub(1, Ordering::SeqCst);
+        self.tracker.notify();
+    }
+}
+
+#[derive(Debug)]
+struct TransferStat {
+    kind: TransferKind,
+    item_id: ItemId,
+    bytes: AtomicU64,
+    window: SyncMutex<SpeedWindow>,
+    progress: SyncMutex<Option<(u64, u64)>>,
+}
+
+/// Speed is measured over windows of at least `SPEED_WINDOW`.
+#[derive(Debug)]
+struct SpeedWindow {
+    start_time: Instant,
+    start_bytes: u64,
+    /// The speed of the last complete window.
+    speed: Option<f64>,
+}
+
+impl TransferStat {
+    const SPEED_WINDOW: Duration = Duration::from_secs(2);
+
+    fn add_bytes(&self, len: u64) {
+        let bytes = self.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        let mut window = self.window.lock().unwrap();
+        let elapsed = window.start_time.elapsed();
+        if Self::SPEED_WINDOW <= elapsed {
+            window.speed = Some((bytes - window.start_bytes) as f64 / elapsed.as_secs_f64());
+            window.start_time = Instant::now();
+            window.start_bytes = bytes;
+        }
+    }
+
+    fn info(&self) -> TransferInfo {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let window = self.window.lock().unwrap();
+        let elapsed = window.start_time.elapsed();
+        // Already a complete window without any progress reported, eg. stalled.
+        let speed = if Self::SPEED_WINDOW <= elapsed {
+            Some((bytes - window.start_bytes) as f64 / elapsed.as_secs_f64())
+        } else {
+            window.speed
+        };
+        TransferInfo {
+            kind: self.kind,
+            item_id: self.item_id.clone(),
+            bytes,
+            speed,
+            progress: *self.progress.lock().unwrap(),
+        }
+    }
+}
+
+/// Entry of an on-disk cache index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheIndexEntry {
+    size: u64,
+    c_tag: Tag,
+}
+
+/// An in-progress upload session persisted in the cache directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UploadSidecar {
+    upload_url: String,
+    /// The end of the last committed part.
+    next_pos: u64,
+    file_size: u64,
+    mtime: SystemTime,
+    /// The c_tag of the remote content which the modification is based on.
+    c_tag: Tag,
+}
+
+/// Sidecar files of upload sessions, so that uploads interrupted by a crash can be resumed in
+/// the next run. Since cache files are anonymous, the content to upload is saved alongside.
+/// They are kept under a dedicated subdirectory of the cache directory.
+#[derive(Debug)]
+struct UploadSidecars {
+    dir: PathBuf,
+}
+
+impl UploadSidecars {
+    const DIR_NAME: &'static str = "uploads";
+    const META_SUFFIX: &'static str = ".upload.json";
+    const DATA_SUFFIX: &'static str = ".upload";
+
+    fn meta_path(&self, item_id: &ItemId) -> PathBuf {
+        self.dir
+            .join(format!("{}{}", item_id.as_str(), Self::META_SUFFIX))
+    }
+
+    fn data_path(&self, item_id: &ItemId) -> PathBuf {
+        self.dir
+            .join(format!("{}{}", item_id.as_str(), Self::DATA_SUFFIX))
+    }
+
+    /// Save the content of `file` and the new session.
+    async fn create(
+        &self,
+        file: &FileCache,
+        guard: &mut FileCacheState,
+        sidecar: &UploadSidecar,
+    ) -> io::Result<()> {
+        file.write_shadow(guard, &self.data_path(&file.item_id))
+            .await?;
+        self.update(&file.item_id, sidecar).await
+    }
+
+    async fn update(&self, item_id: &ItemId, sidecar: &UploadSidecar) -> io::Result<()> {
+        // Never leave a partially written sidecar on crash.
+        let path = self.meta_path(item_id);
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(sidecar)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+
+    /// Remove the sidecar of `item_id`, only if it's of `upload_url` when specified, since
+    /// a newer upload of the same file may already replace it.
+    async fn remove(&self, item_id: &ItemId, upload_url: Option<&str>) {
+        let path = self.meta_path(item_id);
+        if let Some(upload_url) = upload_url {
+            let matched = tokio::fs::read(&path)
+                .await
+                .ok()
+                .and_then(|buf| serde_json::from_slice::<UploadSidecar>(&buf).ok())
+                .is_some_and(|sidecar| sidecar.upload_url == upload_url);
+            if !matched {
                 return;
             }
         }
+        for path in [path, self.data_path(item_id)] {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                if err.kind() != io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove {}: {}", path.display(), err);
+                }
+            }
+        }
     }
 
-    assert_eq!(pos, file_size);
-    log::debug!("Download finished ({} bytes)", file_size);
+    fn scan(&self) -> io::Result<Vec<(ItemId, UploadSidecar)>> {
+        let mut ret = Vec::new();
+        for ent in std::fs::read_dir(&self.dir)? {
+            let path = ent?.path();
+            let item_id = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(Self::META_SUFFIX))
+            {
+                Some(id) => ItemId(id.to_owned()),
+                None => continue,
+            };
+            match std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|buf| Ok(serde_json::from_slice(&buf)?))
+            {
+                Ok(sidecar) => ret.push((item_id, sidecar)),
+                Err(err) => log::error!("Invalid upload sidecar {}: {}", path.display(), err),
+            }
+        }
+        Ok(ret)
+    }
 }
 
+/// A read-only pre-populated cache layer.
+///
+/// The directory contains content files named by their item ids, and an index file
+/// mapping item ids to `CacheIndexEntry`. It is never written or evicted.
 #[derive(Debug)]
-struct DiskCache {
+struct BaseCache {
     dir: PathBuf,
+    index: HashMap<ItemId, CacheIndexEntry>,
+}
+
+impl BaseCache {
+    const INDEX_FILE_NAME: &'static str = "index.json";
+
+    fn load(dir: PathBuf) -> anyhow::Result<Self> {
+        use anyhow::Context as _;
+
+        let index_path = dir.join(Self::INDEX_FILE_NAME);
+        let index = std::fs::read(&index_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|buf| Ok(serde_json::from_slice(&buf)?))
+            .with_context(|| format!("Failed to load base cache index {}", index_path.display()))?;
+        Ok(Self { dir, index })
+    }
+
+    /// Get the content path of an item if it matches the remote side.
+    fn lookup(&self, item_id: &ItemId, meta: &RemoteFileMeta) -> Option<PathBuf> {
+        let ent = self.index.get(item_id)?;
+        if ent.size != meta.size || ent.c_tag != meta.c_tag {
+            return None;
+        }
+        Some(self.dir.join(item_id.as_str()))
+    }
+}
+
+async fn copy_from_base_thread(
+    path: PathBuf,
+    file_size: u64,
+    tx: mpsc::Sender<(u64, Bytes)>,
+    transfer: TransferGuard,
+) {
+    const CHUNK_SIZE: usize = 64 << 10;
+
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("Failed to open base cache file {}: {}", path.display(), err);
+            return;
+        }
+    };
+    let mut pos = 0u64;
+    while pos < file_size {
+        let mut buf = BytesMut::zeroed(CHUNK_SIZE.min((file_size - pos) as usize));
+        if let Err(err) = file.read_exact(&mut buf).await {
+            log::error!("Failed to read base cache file {}: {}", path.display(), err);
+            return;
+        }
+        let chunk_pos = pos;
+        pos += buf.len() as u64;
+        transfer.add_bytes(buf.len() as u64);
+        if tx.send((chunk_pos, buf.freeze())).await.is_err() {
+            return;
+        }
+    }
+    log::debug!(
+        "Copied {} bytes from base cache {}",
+        file_size,
+        path.display()
+    );
+}
+
+/// Cache files kept under a dedicated subdirectory of the cache directory across restarts.
+///
+/// Each content file `<random>.cache` has a sidecar `<random>.json` of `PersistentEntry` only
+/// while it's `Available`. Content files without a sidecar are incomplete or dirty, and are
+/// removed on startup.
+#[derive(Debug)]
+struct PersistentCache {
+    dir: PathBuf,
+    /// Set on shutdown, so that files are kept instead of removed as evicted.
+    closing: AtomicBool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistentEntry {
+    item_id: ItemId,
+    #[serde(flatten)]
+    entry: CacheIndexEntry,
+}
+
+/// A named cache file of `PersistentCache`.
+#[derive(Debug)]
+struct PersistentFile {
+    cache: Arc<PersistentCache>,
+    path: PathBuf,
+}
+
+impl PersistentCache {
+    /// Files outside of it are never touched, since the cache directory is user-configured.
+    const DIR_NAME: &'static str = "persistent";
+    const CONTENT_EXT: &'static str = "cache";
+    const ENTRY_EXT: &'static str = "json";
+
+    fn alloc(self: &Arc<Self>) -> io::Result<(std::fs::File, PersistentFile)> {
+        let (file, path) = tempfile::Builder::new()
+            .suffix(&format!(".{}", Self::CONTENT_EXT))
+            .tempfile_in(&self.dir)?
+            .keep()
+            .map_err(|err| err.error)?;
+        let persist = PersistentFile {
+            cache: self.clone(),
+            path,
+        };
+        Ok((file, persist))
+    }
+
+    /// Load all valid entries, and remove invalid ones.
+    fn scan(&self) -> io::Result<Vec<(std::fs::File, PathBuf, PersistentEntry)>> {
+        let mut ret = Vec::new();
+        for ent in std::fs::read_dir(&self.dir)? {
+            let path = ent?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(Self::CONTENT_EXT) {
+                continue;
+            }
+            let load = || -> anyhow::Result<_> {
+                let entry: PersistentEntry =
+                    serde_json::from_slice(&std::fs::read(path.with_extension(Self::ENTRY_EXT))?)?;
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&path)?;
+                let len = file.metadata()?.len();
+                anyhow::ensure!(len == entry.entry.size, "Length mismatch: {}", len);
+                Ok((file, entry))
+            };
+            match load() {
+                Ok((file, entry)) => ret.push((file, path, entry)),
+                Err(err) => {
+                    log::debug!("Remove invalid cache file {}: {}", path.display(), err);
+                    Self::remove(&path);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn remove(path: &Path) {
+        let _ = std::fs::remove_file(path.with_extension(Self::ENTRY_EXT));
+        if let Err(err) = std::fs::remove_file(path) {
+            log::warn!("Failed to remove cache file {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl PersistentFile {
+    fn save(&self, entry: &PersistentEntry) {
+        let save = || -> io::Result<()> {
+            // Never leave a partially written entry on crash.
+            let tmp_path = self.path.with_extension("tmp");
+            std::fs::write(&tmp_path, serde_json::to_vec(entry)?)?;
+            std::fs::rename(
+                &tmp_path,
+                self.path.with_extension(PersistentCache::ENTRY_EXT),
+            )
+        };
+        if let Err(err) = save() {
+            log::error!(
+                "Failed to save cache entry of {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    fn unsave(&self) {
+        let path = self.path.with_extension(PersistentCache::ENTRY_EXT);
+        if let Err(err) = std::fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::error!("Failed to remove cache entry {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+impl Drop for PersistentFile {
+    fn drop(&mut self) {
+        if !self.cache.closing.load(Ordering::Acquire) {
+            PersistentCache::remove(&self.path);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DiskCache {
+    backend: Box<dyn CacheBackend>,
+    base: Option<BaseCache>,
+    persistent: Option<Arc<PersistentCache>>,
+    /// The lock file guarding the cache directory. The lock is released when it is closed.
+    _lock_file: Option<std::fs::File>,
     total_size: Arc<AtomicU64>,
     cache: SyncMutex<LruCache<ItemId, Arc<FileCache>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    /// Counters of `ExtensionStats` since mount. Cached files and sizes are collected on demand.
+    by_extension: SyncMutex<HashMap<String, ExtensionStats>>,
     config: Config,
+    transfers: Arc<TransferTracker>,
 }
 
 impl DiskCache {
-    fn new(config: Config) -> io::Result<Self> {
+    const LOCK_FILE_NAME: &'static str = ".lock";
+    const ACCOUNT_FILE_NAME: &'static str = ".account";
+
+    /// `account` identifies the drive whose items are cached, so that a cache directory
+    /// is never reused by another account.
+    fn new(config: Config, account: &str, transfers: Arc<TransferTracker>) -> anyhow::Result<Self> {
         let disk_config = &config.disk_cache;
         assert!(disk_config.enable);
         assert!(disk_config.max_cached_file_size <= disk_config.max_total_size);
 
-        let dir = disk_config.path.clone();
-        std::fs::create_dir_all(&dir)?;
-        log::info!("Disk file cache enabled at: {}", dir.display());
-        Ok(Self {
-            dir,
+        let (backend, lock_file) = match disk_config.backend {
+            BackendKind::Disk => {
+                let (backend, lock_file) = Self::open_dir(disk_config)?;
+                Self::check_account(&disk_config.path, account)?;
+                if let Some(sidecars) = &transfers.upload_sidecars {
+                    std::fs::create_dir_all(&sidecars.dir)?;
+                }
+                (Box::new(backend) as Box<dyn CacheBackend>, Some(lock_file))
+            }
+            BackendKind::Memory => {
+                log::info!("Memory file cache enabled");
+                (Box::new(MemoryBackend) as _, None)
+            }
+        };
+
+        let base = match &disk_config.base_path {
+            Some(base_dir) => {
+                let base = BaseCache::load(base_dir.clone())?;
+                log::info!(
+                    "Read-only base cache loaded from {} ({} files)",
+                    base_dir.display(),
+                    base.index.len(),
+                );
+                Some(base)
+            }
+            None => None,
+        };
+
+        let persistent = if disk_config.persistent && disk_config.backend == BackendKind::Disk {
+            let dir = disk_config.path.join(PersistentCache::DIR_NAME);
+            std::fs::create_dir_all(&dir)?;
+            Some(Arc::new(PersistentCache {
+                dir,
+                closing: false.into(),
+            }))
+        } else {
+            None
+        };
+
+        let this = Self {
+            backend,
+            base,
+            persistent,
+            _lock_file: lock_file,
             total_size: Arc::new(0.into()),
             cache: SyncMutex::new(LruCache::new(disk_config.max_files)),
+            hits: 0.into(),
+            misses: 0.into(),
+            evictions: 0.into(),
+            by_extension: SyncMutex::new(HashMap::new()),
             config,
-        })
+            transfers,
+        };
+        if let Some(persistent) = &this.persistent {
+            this.restore_persistent(persistent)?;
+        }
+        Ok(this)
+    }
+
+    /// Load files persisted in the last run as `Available`.
+    /// The first sync invalidates the ones changed on remote side in the meantime.
+    fn restore_persistent(&self, persistent: &Arc<PersistentCache>) -> anyhow::Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        for (cache_file, path, PersistentEntry { item_id, entry }) in persistent.scan()? {
+            let persist = PersistentFile {
+                cache: persistent.clone(),
+                path,
+            };
+            if !self.try_reserve(entry.size) {
+                // Removed on drop.
+                continue;
+            }
+            let (file, _) = FileCache::new(
+                item_id.clone(),
+                entry.size,
+                entry.c_tag,
+                FileCacheStatus::Available,
+                cache_file,
+                &self.total_size,
+                self.transfers.clone(),
+            )
+            .inspect_err(|_| {
+                self.total_size.fetch_sub(entry.size, Ordering::AcqRel);
+            })?;
+            *file.persist.lock().unwrap() = Some(persist);
+            file.unconfirmed.store(true, Ordering::Relaxed);
+            cache.insert(item_id, file);
+        }
+        log::info!("Restored {} files from persistent cache", cache.len());
+        Ok(())
+    }
+
+    /// Allocate a new cache file, which is named only if the cache is persistent.
+    fn alloc_file(&self) -> io::Result<(std::fs::File, Option<PersistentFile>)> {
+        match &self.persistent {
+            Some(persistent) => {
+                let (file, persist) = persistent.alloc()?;
+                Ok((file, Some(persist)))
+            }
+            None => Ok((self.backend.alloc()?, None)),
+        }
+    }
+
+    /// Create and lock the cache directory.
+    fn open_dir(disk_config: &DiskCacheConfig) -> anyhow::Result<(DiskBackend, std::fs::File)> {
+        use anyhow::Context as _;
+        use nix::fcntl::{flock, FlockArg};
+
+        let dir = disk_config.path.clone();
+        std::fs::create_dir_all(&dir)?;
+
+        let lock_path = dir.join(Self::LOCK_FILE_NAME);
+        let lock_file = std::fs::File::create(&lock_path)
+            .with_context(|| format!("Failed to create lock file {}", lock_path.display()))?;
+        match flock(lock_file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(nix::errno::Errno::EWOULDBLOCK) if disk_config.allow_shared_dir => {
+                log::warn!(
+                    "Disk cache directory {} is shared with another running instance",
+                    dir.display(),
+                );
+            }
+            Err(nix::errno::Errno::EWOULDBLOCK) => anyhow::bail!(
+                "Disk cache directory {} is used by another running instance",
+                dir.display(),
+            ),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to lock {}", lock_path.display()))
+            }
+        }
+
+        log::info!("Disk file cache enabled at: {}", dir.display());
+        Ok((DiskBackend::new(dir), lock_file))
+    }
+
+    /// Wipe files left in `dir` by another account, including persistent cache files and
+    /// upload sidecars, since item ids of them refer to items of another drive.
+    /// Only the subdirectories owned by this cache are wiped, since `dir` is user-configured.
+    fn check_account(dir: &Path, account: &str) -> anyhow::Result<()> {
+        use anyhow::Context as _;
+
+        let account_path = dir.join(Self::ACCOUNT_FILE_NAME);
+        match std::fs::read_to_string(&account_path) {
+            Ok(prev) if prev == account => return Ok(()),
+            Ok(_) => {
+                log::warn!(
+                    "Cache directory {} was used by another account, wiping it",
+                    dir.display()
+                );
+                for name in [PersistentCache::DIR_NAME, UploadSidecars::DIR_NAME] {
+                    let path = dir.join(name);
+                    match std::fs::remove_dir_all(&path) {
+                        Ok(()) => {}
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(err) => {
+                            return Err(err)
+                                .with_context(|| format!("Failed to remove {}", path.display()))
+                        }
+                    }
+                }
+            }
+            // A fresh directory. Nothing to wipe.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read {}", account_path.display()))
+            }
+        }
+        std::fs::write(&account_path, account)
+            .with_context(|| format!("Failed to write {}", account_path.display()))?;
+        Ok(())
+    }
+
+    fn record_open(&self, ext: &str, hit: bool) {
+        let mut by_extension = self.by_extension.lock().unwrap();
+        let stats = by_extension.entry(ext.to_owned()).or_default();
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            stats.hits += 1;
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            stats.misses += 1;
+        }
+    }
+
+    fn record_eviction(&self, file: &FileCache) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        let ext = file.extension_group();
+        let mut by_extension = self.by_extension.lock().unwrap();
+        by_extension.entry(ext).or_default().evictions += 1;
+    }
+
+    /// Usage grouped by extensions, the largest first.
+    fn stats_by_extension(&self) -> Vec<(String, ExtensionStats)> {
+        let files = self
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, file)| {
+                let size = file.cached_size.load(Ordering::Relaxed);
+                (file.extension_group(), size)
+            })
+            .collect::<Vec<_>>();
+        let mut map = self.by_extension.lock().unwrap().clone();
+        for (ext, size) in files {
+            let stats = map.entry(ext).or_default();
+            stats.cached_files += 1;
+            stats.cached_size += size;
+        }
+        let mut ret = map.into_iter().collect::<Vec<_>>();
+        ret.sort_by(|(lhs_ext, lhs), (rhs_ext, rhs)| {
+            rhs.cached_size
+                .cmp(&lhs.cached_size)
+                .then_with(|| lhs_ext.cmp(rhs_ext))
+        });
+        ret
     }
 
     fn get(&self, item_id: &ItemId) -> Option<Arc<FileCache>> {
-        self.cache.lock().unwrap().get_mut(item_id).cloned()
+        let file = self.cache.lock().unwrap().get_mut(item_id).cloned()?;
+        file.opens.fetch_add(1, Ordering::Relaxed);
+        Some(file)
+    }
+
+    /// Whether a file of `size` can be cached without evicting others.
+    fn has_room_for(&self, size: u64) -> bool {
+        let disk_config = &self.config.disk_cache;
+        self.total_size.load(Ordering::Relaxed) + size <= disk_config.max_total_size
+            && self.cache.lock().unwrap().len() < disk_config.max_files
+    }
+
+    /// Atomically reserve `size` bytes in the total size, if it fits the budget.
+    fn try_reserve(&self, size: u64) -> bool {
+        let budget = self.config.disk_cache.max_total_size;
+        self.total_size
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+                Some(total + size).filter(|&new| new <= budget)
+            })
+            .is_ok()
+    }
+
+    /// Remove `file` from cache, if it is not already replaced.
+    fn remove(&self, item_id: &ItemId, file: &Arc<FileCache>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .get_mut(item_id)
+            .is_some_and(|cur| Arc::ptr_eq(cur, file))
+        {
+            cache.remove(item_id);
+        }
     }
 
     fn try_alloc_and_fetch(
@@ -629,54 +2583,239 @@ impl DiskCache {
             return Ok(Some(state.clone()));
         }
 
-        // Drop LRU until we have enough space.
-        while self.config.disk_cache.max_cached_file_size
-            < self.total_size.load(Ordering::Relaxed) + file_size
+        // Drop LRU until we have enough space. Evicted files still opened are only released after
+        // closed, so space is reserved atomically to never exceed the budget under concurrency.
+        while !self.try_reserve(file_size) {
+            match self.config.disk_cache.eviction.evict(&mut cache) {
+                Some(evicted) => self.record_eviction(&evicted),
+                // Cache is already empty.
+                None => return Ok(None),
+            }
+        }
+
+        let alloc = || {
+            let (cache_file, persist) = self.alloc_file()?;
+            // This only sets the logical length and creates a sparse file, so no disk space is
+            // allocated up front. The full length is required since reads and the integrity check
+            // rely on it, and space after the downloaded data must read as zeros after `set_len`.
+            cache_file.set_len(file_size)?;
+            FileCache::new(
+                item_id.clone(),
+                file_size,
+                meta.c_tag.clone(),
+                FileCacheStatus::Downloading {
+                    truncate: download_truncate,
+                },
+                cache_file,
+                &self.total_size,
+                self.transfers.clone(),
+            )
+            .map(|(file, pos_tx)| {
+                *file.persist.lock().unwrap() = persist;
+                (file, pos_tx)
+            })
+        };
+        let (file, pos_tx) = alloc().inspect_err(|_| {
+            // Roll back the reservation.
+            self.total_size.fetch_sub(file_size, Ordering::AcqRel);
+        })?;
+
+        // Pending truncation requires a partial download, which is not worth the special handling.
+        let base_path = match truncate_to {
+            None => self
+                .base
+                .as_ref()
+                .and_then(|base| base.lookup(item_id, meta)),
+            Some(_) => None,
+        };
+        let connections =
+            if base_path.is_none() && self.config.download.parallel_min_size <= meta.size {
+                self.config.download.parallel_connections
+            } else {
+                1
+            };
+        // Without a fixed segment size, each connection downloads one segment.
+        let seg_len = match self.config.download.segment_size {
+            size if size != 0 && connections != 1 => size,
+            _ => meta.size.div_ceil(connections as u64),
+        };
+        let segments = split_segments(meta.size, seg_len);
+
+        // The channel size doesn't really matter, since it's just for synchronization
+        // between downloading and writing.
+        let (chunk_tx, chunk_rx) = mpsc::channel(64);
+        *file.remote_hash.lock().unwrap() = meta.quick_xor_hash.clone();
+        // Evict by the policy once `max_files` is reached, instead of `insert` dropping the LRU one.
+        if cache.len() == cache.capacity() {
+            if let Some(evicted) = self.config.disk_cache.eviction.evict(&mut cache) {
+                self.record_eviction(&evicted);
+            }
+        }
+        cache.insert(item_id.clone(), file.clone());
+        tokio::spawn(FileCache::write_to_cache_thread(
+            file.clone(),
+            CacheDownload {
+                chunk_rx,
+                segments: segments.clone(),
+            },
+            pos_tx,
+            onedrive.clone(),
+            client.clone(),
+            event_tx,
+            self.config.upload.clone(),
+        ));
+        if let Some(path) = base_path {
+            log::debug!("Copying {:?} from base cache", item_id);
+            tokio::spawn(copy_from_base_thread(
+                path,
+                meta.size,
+                chunk_tx,
+                self.transfers.start(TransferKind::Download, item_id),
+            ));
+        } else {
+            let workers = connections.min(segments.len());
+            if 1 < segments.len() {
+                log::debug!(
+                    "Downloading {:?} in {} segments with {} connections",
+                    item_id,
+                    segments.len(),
+                    workers,
+                );
+            }
+            // Segments are taken in order, so the downloaded prefix grows steadily.
+            let queue = Arc::new(SyncMutex::new(VecDeque::from(segments)));
+            let transfer = Arc::new(self.transfers.start(TransferKind::Download, item_id));
+            let source = DownloadSource::new(item_id, meta, onedrive);
+            for _ in 0..workers {
+                tokio::spawn(download_worker_thread(
+                    queue.clone(),
+                    source.clone(),
+                    chunk_tx.clone(),
+                    client.clone(),
+                    self.config.download.clone(),
+                    transfer.clone(),
+                ));
+            }
+        }
+        Ok(Some(file))
+    }
+
+    /// Queue uploads interrupted in the last run again, resuming their sessions.
+    async fn resume_uploads(
+        self: Arc<Self>,
+        onedrive: ManagedOnedrive,
+        client: reqwest::Client,
+        event_tx: mpsc::Sender<UpdateEvent>,
+    ) {
+        let sidecars = match &self.transfers.upload_sidecars {
+            Some(sidecars) => sidecars,
+            None => return,
+        };
+        let pending = match sidecars.scan() {
+            Ok(pending) => pending,
+            Err(err) => {
+                log::error!("Failed to scan upload sidecars: {}", err);
+                return;
+            }
+        };
+        for (item_id, sidecar) in pending {
+            if let Err(err) = self
+                .resume_upload(&item_id, sidecar, &onedrive, &client, &event_tx)
+                .await
+            {
+                log::error!("Failed to resume upload of {:?}: {}", item_id, err);
+            }
+        }
+    }
+
+    async fn resume_upload(
+        &self,
+        item_id: &ItemId,
+        sidecar: UploadSidecar,
+        onedrive: &ManagedOnedrive,
+        client: &reqwest::Client,
+        event_tx: &mpsc::Sender<UpdateEvent>,
+    ) -> anyhow::Result<()> {
+        let sidecars = self.transfers.upload_sidecars.as_ref().unwrap();
+
+        let remote_c_tag = match onedrive
+            .get()
+            .await
+            .get_item(ItemLocation::from_id(item_id))
+            .await
         {
-            if cache.remove_lru().is_none() {
-                // Cache is already empty.
-                return Ok(None);
-            }
+            Ok(item) => item.c_tag,
+            Err(err) if err.status_code() == Some(StatusCode::NOT_FOUND) => None,
+            // Keep the sidecar for the next run.
+            Err(err) => return Err(err.into()),
+        };
+        if remote_c_tag.as_ref() != Some(&sidecar.c_tag) {
+            log::warn!(
+                "{:?} is changed or removed on remote side since the interrupted upload, discarded",
+                item_id,
+            );
+            sidecars.remove(item_id, None).await;
+            return Ok(());
         }
 
-        let cache_file = tempfile::tempfile_in(&self.dir)?;
-        cache_file.set_len(file_size)?;
+        let file_size = sidecar.file_size;
+        if !self.try_reserve(file_size) {
+            anyhow::bail!("No room in cache for {} bytes", file_size);
+        }
+        let restore = || {
+            let (mut cache_file, persist) = self.alloc_file()?;
+            let mut data = std::fs::File::open(sidecars.data_path(item_id))?;
+            if io::copy(&mut data, &mut cache_file)? != file_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Saved content is truncated",
+                ));
+            }
+            FileCache::new(
+                item_id.clone(),
+                file_size,
+                sidecar.c_tag.clone(),
+                FileCacheStatus::Available,
+                cache_file,
+                &self.total_size,
+                self.transfers.clone(),
+            )
+            .map(|(file, pos_tx)| {
+                *file.persist.lock().unwrap() = persist;
+                (file, pos_tx)
+            })
+        };
+        let file = match restore() {
+            Ok((file, _)) => file,
+            Err(err) => {
+                self.total_size.fetch_sub(file_size, Ordering::AcqRel);
+                sidecars.remove(item_id, None).await;
+                return Err(err.into());
+            }
+        };
 
-        // The channel size doesn't really matter, since it's just for synchronization
-        // between downloading and writing.
-        let (chunk_tx, chunk_rx) = mpsc::channel(64);
-        let (file, pos_tx) = FileCache::new(
-            item_id.clone(),
-            file_size,
-            meta.c_tag.clone(),
-            FileCacheStatus::Downloading {
-                truncate: download_truncate,
-            },
-            cache_file.into(),
-            &self.total_size,
-        );
-        cache.insert(item_id.clone(), file.clone());
-        tokio::spawn(FileCache::write_to_cache_thread(
-            file.clone(),
-            chunk_rx,
-            pos_tx,
-            onedrive,
+        log::info!("Resuming the interrupted upload of {:?}", item_id);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(item_id.clone(), file.clone());
+        let mut guard = file.state.lock().await;
+        file.queue_upload(
+            &mut guard,
+            sidecar.mtime,
+            onedrive.clone(),
             client.clone(),
-            event_tx,
+            event_tx.clone(),
             self.config.upload.clone(),
-        ));
-        tokio::spawn(download_thread(
-            meta.size,
-            meta.download_url.clone(),
-            chunk_tx,
-            client,
-            self.config.download.clone(),
-        ));
-        Ok(Some(file))
+        );
+        if let FileCacheStatus::Dirty { resume, .. } = &mut guard.status {
+            *resume = Some(sidecar);
+        }
+        Ok(())
     }
 
     async fn insert_empty(&self, item_id: ItemId, c_tag: Tag) -> Result<Arc<FileCache>> {
-        let cache_file = tempfile::tempfile_in(&self.dir)?;
+        let (cache_file, persist) = self.alloc_file()?;
         let (file, old) = {
             let mut cache = self.cache.lock().unwrap();
             let (file, _) = FileCache::new(
@@ -684,14 +2823,18 @@ impl DiskCache {
                 0,
                 c_tag,
                 FileCacheStatus::Available,
-                cache_file.into(),
+                cache_file,
                 &self.total_size,
-            );
+                self.transfers.clone(),
+            )?;
+            *file.remote_hash.lock().unwrap() = Some(QuickXorHash::new().finish());
+            *file.persist.lock().unwrap() = persist;
             let old = cache.insert(item_id, file.clone());
             (file, old)
         };
         if let Some(old) = old {
-            old.state.lock().await.status = FileCacheStatus::Invalidated;
+            let mut guard = old.state.lock().await;
+            old.set_status(&mut guard, FileCacheStatus::Invalidated);
         }
         Ok(file)
     }
@@ -713,16 +2856,23 @@ impl DiskCache {
                     Some(file) => file,
                     None => continue,
                 };
-                if item.deleted.is_some() {
-                    log::debug!("Cached file {:?} is deleted", file.item_id);
+                if let Some(deletion) = Deletion::of(item) {
+                    log::debug!("Cached file {:?} is {}", file.item_id, deletion);
                     outdated.push(cache.remove(&id).unwrap());
                     continue;
                 }
 
-                let c_tag = item.c_tag.clone().expect("Missing c_tag");
+                let c_tag = match item.c_tag.clone() {
+                    Some(c_tag) => c_tag,
+                    None => {
+                        log::warn!("Skip cached file {:?} without c_tag in sync", file.item_id);
+                        continue;
+                    }
+                };
                 let old_c_tag = file.c_tag.lock().unwrap();
                 if *old_c_tag == c_tag {
                     log::debug!("Cached file {:?} is still up-to-date", *old_c_tag);
+                    file.unconfirmed.store(false, Ordering::Relaxed);
                 } else {
                     log::debug!(
                         "Cached file {:?} is outdated, ctag: {:?} -> {:?}",
@@ -736,7 +2886,17 @@ impl DiskCache {
             }
         }
         for file in outdated {
-            file.state.lock().await.status = FileCacheStatus::Invalidated;
+            let mut guard = file.state.lock().await;
+            file.set_status(&mut guard, FileCacheStatus::Invalidated);
+        }
+    }
+
+    async fn invalidate(&self, item_id: &ItemId) {
+        let file = self.cache.lock().unwrap().remove(item_id);
+        if let Some(file) = file {
+            log::debug!("Cached file {:?} is removed", item_id);
+            let mut guard = file.state.lock().await;
+            file.set_status(&mut guard, FileCacheStatus::Invalidated);
         }
     }
 }
@@ -744,9 +2904,44 @@ impl DiskCache {
 #[derive(Debug)]
 struct FileCache {
     state: Mutex<FileCacheState>,
+    /// File size if the status is `Available`, or `NOT_AVAILABLE` otherwise.
+    /// It is updated on every status transition, so that reads on available files can bypass
+    /// `state` and use positioned reads on `raw_file` concurrently.
+    available_file_size: AtomicU64,
+    /// Held in shared mode by lock-free reads, and in exclusive mode by status transitions,
+    /// so that no lock-free read is in flight once the file leaves `Available`.
+    fast_read_lock: SyncRwLock<()>,
+    /// A duplicated handle of `cache_file` for positioned reads.
+    raw_file: std::fs::File,
     item_id: ItemId,
     c_tag: SyncMutex<Tag>,
+    /// QuickXorHash of the content on remote side, if known.
+    remote_hash: SyncMutex<Option<String>>,
+    /// Recycled read buffer. Its memory is reclaimed once all `Bytes` split from it are dropped.
+    read_buf: SyncMutex<BytesMut>,
+    /// The end of the range already read ahead, or being read ahead.
+    read_ahead_pos: AtomicU64,
+    /// The named cache file if the cache is persistent, which is saved while `Available`.
+    persist: SyncMutex<Option<PersistentFile>>,
+    /// Whether it's restored from the persistent cache, and its c_tag is not yet confirmed by
+    /// sync. It's revalidated on the next open if so.
+    unconfirmed: AtomicBool,
+    /// Number of opens after it's cached, for `EvictionPolicy::Lfu`.
+    opens: AtomicU64,
+    /// `extension_group` of its name when last opened, or `None` if it's never opened.
+    extension: SyncMutex<Option<String>>,
+    /// A copy of `file_size` readable without locking `state`, for `EvictionPolicy::SizeWeighted`.
+    cached_size: AtomicU64,
     cache_total_size: Weak<AtomicU64>,
+    transfers: Arc<TransferTracker>,
+}
+
+/// Chunks downloaded into a cache file, with their offsets.
+#[derive(Debug)]
+struct CacheDownload {
+    chunk_rx: mpsc::Receiver<(u64, Bytes)>,
+    /// The rest range of each segment, advanced as chunks are written.
+    segments: Vec<Range<u64>>,
 }
 
 #[derive(Debug)]
@@ -754,9 +2949,27 @@ struct FileCacheState {
     status: FileCacheStatus,
     file_size: u64,
     available_size: watch::Receiver<u64>,
+    /// The offset the download may run ahead to before pausing. It only grows.
+    prefetch_end: watch::Sender<u64>,
     cache_file: tokio::fs::File,
 }
 
+impl FileCacheState {
+    /// Let the download run at least to `end`.
+    fn want(&self, end: u64) {
+        self.prefetch_end.send_if_modified(|cur| {
+            let modified = *cur < end;
+            *cur = (*cur).max(end);
+            modified
+        });
+    }
+
+    /// Let the download run to the end, for waiters of the whole file.
+    fn want_all(&self) {
+        self.want(u64::MAX);
+    }
+}
+
 #[derive(Debug)]
 enum FileCacheStatus {
     /// File is downloading.
@@ -774,46 +2987,214 @@ enum FileCacheStatus {
         flush_tx: Option<oneshot::Sender<()>>,
         /// When closed, `true` indicates a successful upload, while `false` indicates still dirty.
         done_rx: watch::Receiver<bool>,
+        /// The session to resume, if it's restored from an interrupted upload.
+        resume: Option<UploadSidecar>,
     },
     /// File is changed in remote side, local cache is invalidated.
     Invalidated,
 }
 
 impl FileCache {
+    const NOT_AVAILABLE: u64 = u64::MAX;
+
+    fn extension_group(&self) -> String {
+        match &*self.extension.lock().unwrap() {
+            Some(ext) => ext.clone(),
+            None => extension_group(None),
+        }
+    }
+
     fn new(
         item_id: ItemId,
         file_size: u64,
         c_tag: Tag,
         status: FileCacheStatus,
-        cache_file: tokio::fs::File,
+        cache_file: std::fs::File,
         cache_total_size: &Arc<AtomicU64>,
-    ) -> (Arc<Self>, watch::Sender<u64>) {
+        transfers: Arc<TransferTracker>,
+    ) -> io::Result<(Arc<Self>, watch::Sender<u64>)> {
+        let raw_file = cache_file.try_clone()?;
         let (pos_tx, pos_rx) = watch::channel(0);
-        cache_total_size.fetch_add(file_size, Ordering::Relaxed);
+        // Pending truncations are uploaded after the download, so they are never paused.
+        let prefetch_end = match status {
+            FileCacheStatus::Downloading { truncate: None } if transfers.prefetch_bytes != 0 => {
+                transfers.prefetch_bytes
+            }
+            _ => u64::MAX,
+        };
+        // `file_size` is already reserved in `cache_total_size` by the caller, and is released
+        // on drop.
+        let available_file_size = match status {
+            FileCacheStatus::Available => file_size,
+            _ => Self::NOT_AVAILABLE,
+        };
         let this = Arc::new(Self {
             state: Mutex::new(FileCacheState {
                 status,
                 file_size,
                 available_size: pos_rx,
-                cache_file,
+                prefetch_end: watch::channel(prefetch_end).0,
+                cache_file: cache_file.into(),
             }),
+            available_file_size: available_file_size.into(),
+            fast_read_lock: SyncRwLock::new(()),
+            raw_file,
             item_id,
             c_tag: SyncMutex::new(c_tag),
+            remote_hash: SyncMutex::new(None),
+            read_buf: SyncMutex::new(BytesMut::new()),
+            read_ahead_pos: 0.into(),
+            persist: SyncMutex::new(None),
+            unconfirmed: false.into(),
+            opens: 0.into(),
+            extension: SyncMutex::new(None),
+            cached_size: file_size.into(),
             cache_total_size: Arc::downgrade(cache_total_size),
+            transfers,
         });
-        (this, pos_tx)
+        Ok((this, pos_tx))
+    }
+
+    /// Transit to a new status. All status changes should go through this method.
+    ///
+    /// Content must only be modified after leaving `Available`, since it waits for in-flight
+    /// lock-free reads here.
+    fn set_status(&self, guard: &mut FileCacheState, status: FileCacheStatus) {
+        let available_file_size = match status {
+            FileCacheStatus::Available => guard.file_size,
+            _ => Self::NOT_AVAILABLE,
+        };
+        {
+            let _fast_read = self.fast_read_lock.write().unwrap();
+            self.available_file_size
+                .store(available_file_size, Ordering::Release);
+        }
+        if let Some(persist) = &*self.persist.lock().unwrap() {
+            let is_available =
+                |status: &FileCacheStatus| matches!(status, FileCacheStatus::Available);
+            match (is_available(&guard.status), is_available(&status)) {
+                (false, true) => persist.save(&PersistentEntry {
+                    item_id: self.item_id.clone(),
+                    entry: CacheIndexEntry {
+                        size: guard.file_size,
+                        c_tag: self.c_tag.lock().unwrap().clone(),
+                    },
+                }),
+                (true, false) => persist.unsave(),
+                _ => {}
+            }
+        }
+        let is_dirty = |status: &FileCacheStatus| matches!(status, FileCacheStatus::Dirty { .. });
+        match (is_dirty(&guard.status), is_dirty(&status)) {
+            (false, true) => self.transfers.dirty_files.fetch_add(1, Ordering::SeqCst),
+            (true, false) => self.transfers.dirty_files.fetch_sub(1, Ordering::SeqCst),
+            _ => 0,
+        };
+        guard.status = status;
+    }
+
+    /// Whether the content of the cache file is the same as the remote side.
+    async fn is_same_as_remote(&self, guard: &mut FileCacheState) -> bool {
+        let remote_hash = match self.remote_hash.lock().unwrap().clone() {
+            Some(hash) => hash,
+            None => return false,
+        };
+        let mut hasher = QuickXorHash::new();
+        let mut buf = vec![0u8; 64 << 10];
+        let mut rest = guard.file_size;
+        guard.cache_file.seek(SeekFrom::Start(0)).await.unwrap();
+        while rest != 0 {
+            let len = rest.min(buf.len() as u64) as usize;
+            guard.cache_file.read_exact(&mut buf[..len]).await.unwrap();
+            hasher.update(&buf[..len]);
+            rest -= len as u64;
+        }
+        hasher.finish() == remote_hash
+    }
+
+    /// Check a completed download against the remote hash, if `verify_hash` is set and the hash
+    /// is known. Downloads with pending truncation are not checked, since they are partial.
+    async fn verify_download(&self, guard: &mut FileCacheState) -> bool {
+        if !self.transfers.verify_hash
+            || !matches!(
+                guard.status,
+                FileCacheStatus::Downloading { truncate: None }
+            )
+            || self.remote_hash.lock().unwrap().is_none()
+        {
+            return true;
+        }
+        if self.is_same_as_remote(guard).await {
+            return true;
+        }
+        log::error!(
+            "Download of {:?} mismatches the remote hash {:?}",
+            self.item_id,
+            self.remote_hash.lock().unwrap(),
+        );
+        false
+    }
+
+    /// Copy the content of the cache file to `path`, creating parent directories if necessary.
+    async fn write_shadow(&self, guard: &mut FileCacheState, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut shadow = tokio::fs::File::create(path).await?;
+        guard.cache_file.seek(SeekFrom::Start(0)).await?;
+        let mut src = (&mut guard.cache_file).take(guard.file_size);
+        tokio::io::copy(&mut src, &mut shadow).await?;
+        shadow.flush().await
+    }
+
+    /// Check the length of the cache file is not changed externally, or invalidate it.
+    fn verify_intact(&self, guard: &mut FileCacheState) -> bool {
+        if !self.transfers.verify_cache_file {
+            return true;
+        }
+        let len = match self.raw_file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(err) => {
+                log::error!("Failed to stat cache file of {:?}: {}", self.item_id, err);
+                return true;
+            }
+        };
+        if len == guard.file_size {
+            return true;
+        }
+        log::error!(
+            "Cache file of {:?} is modified externally, expecting {} bytes but got {}. Invalidated",
+            self.item_id,
+            guard.file_size,
+            len,
+        );
+        self.set_status(guard, FileCacheStatus::Invalidated);
+        false
     }
 
+    /// Write downloaded chunks into the cache file. `pos_tx` only advances with the contiguous
+    /// prefix of completed segments.
     async fn write_to_cache_thread(
         this: Arc<FileCache>,
-        mut chunk_rx: mpsc::Receiver<Bytes>,
+        download: CacheDownload,
         pos_tx: watch::Sender<u64>,
         onedrive: ManagedOnedrive,
         client: reqwest::Client,
         event_tx: mpsc::Sender<UpdateEvent>,
         upload_config: UploadConfig,
     ) {
-        let mut pos = 0u64;
+        let CacheDownload {
+            mut chunk_rx,
+            mut segments,
+        } = download;
+        // The end of the downloaded prefix.
+        let prefix = |segments: &[Range<u64>]| {
+            segments
+                .iter()
+                .find(|seg| !seg.is_empty())
+                .map_or(u64::MAX, |seg| seg.start)
+        };
+        let mut prefetch_rx = this.state.lock().await.prefetch_end.subscribe();
 
         let complete = |mut guard: MutexGuard<'_, FileCacheState>, download_size: u64| {
             log::debug!(
@@ -843,13 +3224,31 @@ impl FileCache {
                     );
                 }
                 FileCacheStatus::Downloading { truncate: None } => {
-                    guard.status = FileCacheStatus::Available;
+                    this.set_status(&mut guard, FileCacheStatus::Available);
                 }
                 _ => unreachable!(),
             }
         };
 
-        while let Some(mut chunk) = chunk_rx.recv().await {
+        loop {
+            // Pause once the window ahead of reads is filled, eg. on random reads behind the
+            // downloaded data, so that bandwidth is not spent on content never read.
+            // Wake up periodically to stop if removed from cache in the meantime.
+            // Never pause once all segments are done, eg. for empty files, or it never completes.
+            const PAUSE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+            while prefix(&segments) != u64::MAX
+                && *prefetch_rx.borrow_and_update() <= prefix(&segments)
+            {
+                if Arc::strong_count(&this) == 1 {
+                    return;
+                }
+                let _ = time::timeout(PAUSE_CHECK_PERIOD, prefetch_rx.changed()).await;
+            }
+
+            let (offset, mut chunk) = match chunk_rx.recv().await {
+                Some(chunk) => chunk,
+                None => break,
+            };
             let mut guard = this.state.lock().await;
             let download_size = match guard.status {
                 FileCacheStatus::Downloading {
@@ -862,27 +3261,38 @@ impl FileCache {
                     guard.file_size
                 }
                 FileCacheStatus::Downloading { .. } | FileCacheStatus::Invalidated => return,
-                FileCacheStatus::DownloadFailed { .. }
+                FileCacheStatus::DownloadFailed
                 | FileCacheStatus::Available
                 | FileCacheStatus::Dirty { .. } => unreachable!(),
             };
             assert!(download_size <= guard.file_size);
 
+            let seg = segments
+                .iter_mut()
+                .find(|seg| seg.start == offset && !seg.is_empty())
+                .expect("Chunks are sequential in each segment");
+            seg.start += chunk.len() as u64;
+
             // Truncate extra data if `set_len` is called.
-            let rest_len = download_size.saturating_sub(pos);
+            let rest_len = download_size.saturating_sub(offset);
             if rest_len < chunk.len() as u64 {
                 chunk.truncate(rest_len as usize);
             }
 
             if !chunk.is_empty() {
-                guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
+                guard
+                    .cache_file
+                    .seek(SeekFrom::Start(offset))
+                    .await
+                    .unwrap();
                 guard.cache_file.write_all(&chunk).await.unwrap();
-                pos += chunk.len() as u64;
             }
+            let pos = prefix(&segments);
             log::trace!(
-                "Write {} bytes to cache {:?}, current pos: {}, total need download: {}, file size: {}",
+                "Write {} bytes to cache {:?} at {}, available: {}, total need download: {}, file size: {}",
                 chunk.len(),
                 this.item_id,
+                offset,
                 pos,
                 download_size,
                 guard.file_size,
@@ -892,6 +3302,12 @@ impl FileCache {
                 // We are holding `state`.
                 pos_tx.send(pos).unwrap();
             } else {
+                // Checked before it's available, though reads of downloaded parts may have
+                // been served already.
+                if !this.verify_download(&mut guard).await {
+                    this.set_status(&mut guard, FileCacheStatus::DownloadFailed);
+                    return;
+                }
                 // We are holding `state`.
                 // The file size may be larger then download size due to set_len.
                 // Space after data written is already zero as expected.
@@ -908,11 +3324,12 @@ impl FileCache {
                 truncate.map(|(sz, _)| sz).unwrap_or(guard.file_size)
             }
             FileCacheStatus::Invalidated => return,
-            FileCacheStatus::DownloadFailed { .. }
+            FileCacheStatus::DownloadFailed
             | FileCacheStatus::Available
             | FileCacheStatus::Dirty { .. } => unreachable!(),
         };
 
+        let pos = prefix(&segments);
         if pos < download_size {
             log::error!(
                 "Download failed of {:?}, got {}/{}",
@@ -920,26 +3337,138 @@ impl FileCache {
                 pos,
                 download_size,
             );
-            guard.status = FileCacheStatus::DownloadFailed;
+            this.set_status(&mut guard, FileCacheStatus::DownloadFailed);
+        } else if !this.verify_download(&mut guard).await {
+            this.set_status(&mut guard, FileCacheStatus::DownloadFailed);
         } else {
             // File is set to a larger length than remote side.
             complete(guard, download_size);
         }
     }
 
-    async fn read(this: &Arc<Self>, offset: u64, size: usize) -> Result<Bytes> {
+    /// Get a zeroed buffer of `len` bytes, which is taken from `read_buf` if `reuse` is set.
+    fn alloc_read_buf(&self, len: usize, reuse: bool) -> BytesMut {
+        if !reuse {
+            return BytesMut::zeroed(len);
+        }
+        // Concurrent readers get an empty buffer here and simply allocate a new one.
+        let mut buf = std::mem::take(&mut *self.read_buf.lock().unwrap());
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Split out the filled content and put the rest capacity back to `read_buf`.
+    fn recycle_read_buf(&self, mut buf: BytesMut, reuse: bool) -> Bytes {
+        let ret = buf.split().freeze();
+        if reuse {
+            *self.read_buf.lock().unwrap() = buf;
+        }
+        ret
+    }
+
+    /// Pre-read blocks after `pos` in parallel, to warm the OS page cache for sequential reads.
+    /// This hides the latency of slow cache directories, eg. on network storage.
+    fn read_ahead(self: &Arc<Self>, pos: u64, file_size: u64) {
+        const READ_AHEAD_BLOCK_SIZE: u64 = 1 << 20;
+
+        let workers = self.transfers.read_ahead_workers as u64;
+        if workers == 0 {
+            return;
+        }
+        let window = workers * READ_AHEAD_BLOCK_SIZE;
+        // Refill only when half of the window is consumed.
+        let ahead = self.read_ahead_pos.load(Ordering::Relaxed);
+        if file_size <= ahead || pos + window / 2 < ahead {
+            return;
+        }
+        let window_end = file_size.min(pos + window);
+        let start = self
+            .read_ahead_pos
+            .fetch_max(window_end, Ordering::Relaxed)
+            .max(pos);
+        for block_start in (start..window_end).step_by(READ_AHEAD_BLOCK_SIZE as usize) {
+            let len = (window_end - block_start).min(READ_AHEAD_BLOCK_SIZE) as usize;
+            let this = self.clone();
+            tokio::task::spawn_blocking(move || {
+                let _fast_read = this.fast_read_lock.read().unwrap();
+                if this.available_file_size.load(Ordering::Acquire) != file_size {
+                    return;
+                }
+                let mut buf = vec![0u8; len];
+                if let Err(err) = this.raw_file.read_exact_at(&mut buf, block_start) {
+                    log::debug!("Failed to read ahead {:?}: {}", this.item_id, err);
+                }
+            });
+        }
+    }
+
+    async fn read(
+        this: &Arc<Self>,
+        offset: u64,
+        size: usize,
+        reuse_buf: bool,
+        read_ahead: bool,
+        short_read: bool,
+    ) -> Result<Bytes> {
+        // Fast path for available files without locking `state`.
+        let file_size = this.available_file_size.load(Ordering::Acquire);
+        if file_size != Self::NOT_AVAILABLE {
+            if file_size <= offset || size == 0 {
+                return Ok(Bytes::new());
+            }
+            let end = file_size.min(offset + size as u64);
+            let this = this.clone();
+            let mut buf = this.alloc_read_buf((end - offset) as usize, reuse_buf);
+            let (this, buf) = tokio::task::spawn_blocking(move || {
+                let fast_read = this.fast_read_lock.read().unwrap();
+                // The file may be modified or truncated since the check above.
+                // Let the slow path handle files modified externally.
+                if this.available_file_size.load(Ordering::Acquire) != file_size
+                    || (this.transfers.verify_cache_file
+                        && this.raw_file.metadata()?.len() != file_size)
+                {
+                    drop(fast_read);
+                    return io::Result::Ok((this, None));
+                }
+                this.raw_file.read_exact_at(&mut buf, offset)?;
+                drop(fast_read);
+                Ok((this, Some(buf)))
+            })
+            .await
+            .unwrap()?;
+            if let Some(buf) = buf {
+                if read_ahead {
+                    this.read_ahead(end, file_size);
+                }
+                return Ok(this.recycle_read_buf(buf, reuse_buf));
+            }
+        }
+
         let mut guard = this.state.lock().await;
         let file_size = guard.file_size;
         if file_size <= offset || size == 0 {
             return Ok(Bytes::new());
         }
-        let end = offset + size as u64;
+        // Reads straddling EOF are short, and only wait for bytes before EOF.
+        let mut end = file_size.min(offset + size as u64);
 
+        if let FileCacheStatus::Downloading { .. } = guard.status {
+            guard.want(end.saturating_add(this.transfers.prefetch_bytes));
+        }
         match guard.status {
+            // Failed uploads are retried in `Dirty` state, and the local content is always the
+            // latest one, so it's served during the upload and retries.
             FileCacheStatus::Available | FileCacheStatus::Dirty { .. } => {}
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } if end <= *guard.available_size.borrow() => {}
+            // Return the downloaded prefix at once. The caller requests the rest again.
+            FileCacheStatus::Downloading { .. }
+                if short_read && offset < *guard.available_size.borrow() =>
+            {
+                end = *guard.available_size.borrow();
+            }
             FileCacheStatus::Downloading { .. } => {
                 let mut rx = guard.available_size.clone();
                 drop(guard);
@@ -957,17 +3486,25 @@ impl FileCache {
             }
         }
 
+        if !this.verify_intact(&mut guard) {
+            return Err(Error::Invalidated);
+        }
+
         // File size should be retrieved after waiting since it may change.
+        // It may be even truncated before `offset`.
+        if guard.file_size <= offset {
+            return Ok(Bytes::new());
+        }
         let end = end.min(guard.file_size);
 
-        let mut buf = vec![0u8; (end - offset) as usize];
+        let mut buf = this.alloc_read_buf((end - offset) as usize, reuse_buf);
         guard
             .cache_file
             .seek(SeekFrom::Start(offset))
             .await
             .unwrap();
         guard.cache_file.read_exact(&mut buf).await.unwrap();
-        Ok(buf.into())
+        Ok(this.recycle_read_buf(buf, reuse_buf))
     }
 
     async fn write(
@@ -988,6 +3525,7 @@ impl FileCache {
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } => {
+                guard.want_all();
                 let mut rx = guard.available_size.clone();
                 drop(guard);
                 // Wait until finished.
@@ -1020,19 +3558,15 @@ impl FileCache {
             .unwrap();
         guard.cache_file.write_all(data).await.unwrap();
 
+        // Writes past the end grow the file, even if it was just truncated.
         let new_size = guard.file_size.max(offset + data.len() as u64);
-        if guard.file_size < new_size {
-            if let Some(total) = this.cache_total_size.upgrade() {
-                total.fetch_add(new_size - guard.file_size, Ordering::Relaxed);
-            }
-        }
         log::debug!(
             "Cached file {:?} is dirty, size: {} -> {}",
             this.item_id,
             guard.file_size,
             new_size,
         );
-        guard.file_size = new_size;
+        this.set_file_size(&mut guard, new_size);
 
         Ok(UpdatedFileAttr {
             item_id: this.item_id.clone(),
@@ -1043,6 +3577,52 @@ impl FileCache {
         })
     }
 
+    /// Start the pending upload without waiting for the flush delay.
+    async fn flush_now(&self) {
+        if let FileCacheStatus::Dirty { flush_tx, .. } = &mut self.state.lock().await.status {
+            if let Some(flush_tx) = flush_tx.take() {
+                let _ = flush_tx.send(());
+            }
+        }
+    }
+
+    /// Set the logical file size, and keep the total size of the cache in sync.
+    /// The file length itself is not changed.
+    fn set_file_size(&self, guard: &mut FileCacheState, new_size: u64) {
+        if let Some(total) = self.cache_total_size.upgrade() {
+            if guard.file_size < new_size {
+                total.fetch_add(new_size - guard.file_size, Ordering::Relaxed);
+            } else {
+                total.fetch_sub(guard.file_size - new_size, Ordering::Relaxed);
+            }
+        }
+        guard.file_size = new_size;
+        self.cached_size.store(new_size, Ordering::Relaxed);
+    }
+
+    /// Report `uploaded` of `total` bytes are committed, to the status and as events.
+    /// Events are best-effort, and are skipped if the sync thread is busy.
+    fn report_upload_progress(
+        &self,
+        transfer: &TransferGuard,
+        progress: &mut ProgressThrottle,
+        event_tx: &mpsc::Sender<UpdateEvent>,
+        uploaded: u64,
+        total: u64,
+    ) {
+        transfer.set_progress(uploaded, total);
+        if progress.should_send(Instant::now(), uploaded, total) {
+            let event = UpdateEvent::UploadProgress {
+                item_id: self.item_id.clone(),
+                uploaded,
+                total,
+            };
+            if let Err(err) = event_tx.try_send(event) {
+                log::debug!("Skip upload progress of {:?}: {}", self.item_id, err);
+            }
+        }
+    }
+
     fn queue_upload(
         self: &Arc<Self>,
         guard: &mut MutexGuard<'_, FileCacheState>,
@@ -1052,128 +3632,351 @@ impl FileCache {
         event_tx: mpsc::Sender<UpdateEvent>,
         config: UploadConfig,
     ) {
-        const UPLOAD_PART_SIZE: usize = 10 << 20;
-        static_assertions::const_assert!(
-            UPLOAD_PART_SIZE <= onedrive_api::UploadSession::MAX_PART_SIZE,
-        );
-
         let (flush_tx, flush_rx) = oneshot::channel();
         let (done_tx, done_rx) = watch::channel(false);
         let init_lock_mtime = Instant::now();
-        guard.status = FileCacheStatus::Dirty {
-            lock_mtime: init_lock_mtime,
-            flush_tx: Some(flush_tx),
-            done_rx,
-        };
+        self.set_status(
+            guard,
+            FileCacheStatus::Dirty {
+                lock_mtime: init_lock_mtime,
+                flush_tx: Some(flush_tx),
+                done_rx,
+                resume: None,
+            },
+        );
 
         let this = self.clone();
+        let transfer = self.transfers.start(TransferKind::Upload, &self.item_id);
         tokio::spawn(async move {
-            let _ = time::timeout(config.flush_delay, flush_rx).await;
+            // Uploads explicitly flushed are in foreground, and are not limited by the
+            // background rate limit.
+            let foreground = matches!(
+                time::timeout(config.flush_delay, flush_rx).await,
+                Ok(Ok(()))
+            );
+            let background_limiter = if foreground {
+                None
+            } else {
+                RateLimiter::new(config.background_max_bytes_per_sec)
+            };
 
             let is_up_to_date = |status: &FileCacheStatus| matches!(status, FileCacheStatus::Dirty { lock_mtime, .. } if *lock_mtime == init_lock_mtime);
 
-            loop {
+            // Failed attempts, reported along with errors.
+            let mut attempt = 0u32;
+            let mut progress = ProgressThrottle::default();
+            'upload: loop {
                 // Check not changed since last lock.
-                let file_size = {
-                    let guard = this.state.lock().await;
-                    if !is_up_to_date(&guard.status) {
+                let (file_size, resume) = {
+                    let mut guard = this.state.lock().await;
+                    if !is_up_to_date(&guard.status) || !this.verify_intact(&mut guard) {
                         return;
                     }
-                    guard.file_size
+                    let resume = match &mut guard.status {
+                        FileCacheStatus::Dirty { resume, .. } => resume.take(),
+                        _ => None,
+                    };
+                    // Transient edits may be reverted before the upload.
+                    if config.skip_unchanged && this.is_same_as_remote(&mut guard).await {
+                        log::info!(
+                            "Content of {:?} is the same as remote side, skip uploading",
+                            this.item_id,
+                        );
+                        this.set_status(&mut guard, FileCacheStatus::Available);
+                        let _ = done_tx.send(true);
+                        return;
+                    }
+                    (guard.file_size, resume)
                 };
 
-                // Create upload session.
                 log::info!("Uploading {:?} ({} B)", this.item_id, file_size);
                 let mut initial = DriveItem::default();
                 initial.file_system_info = Some(Box::new(serde_json::json!({
                     "lastModifiedDateTime": humantime::format_rfc3339_seconds(mtime).to_string(),
                 })));
-                let sess = match onedrive
-                    .get()
-                    .await
-                    .new_upload_session_with_initial_option(
-                        ItemLocation::from_id(&this.item_id),
-                        &initial,
-                        DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
-                    )
-                    .await
-                {
-                    Ok((sess, _)) => sess,
-                    Err(err) => {
-                        log::error!(
-                            "Failed to create upload session of {:?} ({} B), retrying: {}",
-                            this.item_id,
-                            file_size,
-                            err,
-                        );
-                        // Retry
-                        time::sleep(config.retry_delay).await;
-                        continue;
-                    }
-                };
-
-                // Upload parts.
-                let mut pos = 0u64;
-                let mut buf = vec![0u8; UPLOAD_PART_SIZE];
-                let item = loop {
-                    let end = file_size.min(pos + UPLOAD_PART_SIZE as u64);
-                    let len = (end - pos) as usize;
+                this.report_upload_progress(&transfer, &mut progress, &event_tx, 0, file_size);
+                let item = if file_size <= OneDrive::UPLOAD_SMALL_MAX_SIZE as u64 {
+                    // Small files, including empty ones, are uploaded in a single request.
+                    // The modification time is set afterwards since it cannot be carried.
+                    let mut buf = this.transfers.upload_buffers.get(file_size as usize).await;
                     {
                         let mut guard = this.state.lock().await;
                         if !is_up_to_date(&guard.status) {
-                            log::debug!("Upload session of {:?} outdates", this.item_id);
-                            if let Err(err) = sess.delete(onedrive.get().await.client()).await {
-                                log::error!(
-                                    "Failed to delete outdated upload session of {:?}: {}",
-                                    this.item_id,
-                                    err,
-                                );
-                            }
+                            log::debug!("Upload of {:?} outdates", this.item_id);
                             return;
                         }
                         assert_eq!(file_size, guard.file_size, "Truncation restarts uploading");
-                        guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
-                        guard.cache_file.read_exact(&mut buf[..len]).await.unwrap();
+                        guard.cache_file.seek(SeekFrom::Start(0)).await.unwrap();
+                        guard.cache_file.read_exact(&mut buf.buf).await.unwrap();
                     }
 
-                    match sess
-                        .upload_part(buf[..len].to_owned(), pos..end, file_size, &client)
-                        .await
+                    for limiter in [&this.transfers.upload_limiter, &background_limiter]
+                        .into_iter()
+                        .flatten()
                     {
-                        Ok(None) => {
-                            assert_ne!(end, file_size);
-                            log::debug!(
-                                "Uploaded part {}..{}/{} of file {:?}",
-                                pos,
-                                end,
-                                file_size,
-                                this.item_id,
-                            );
-                            pos = end;
-                        }
-                        Ok(Some(item)) => {
-                            assert_eq!(end, file_size);
-                            break item;
+                        limiter.acquire(file_size).await;
+                    }
+
+                    let onedrive = onedrive.get().await;
+                    let loc = ItemLocation::from_id(&this.item_id);
+                    let ret = match onedrive.upload_small(loc, buf.buf.split().freeze()).await {
+                        Ok(_) => onedrive.update_item(loc, &initial).await,
+                        Err(err) => Err(err),
+                    };
+                    match ret {
+                        Ok(item) => {
+                            transfer.add_bytes(file_size);
+                            item
                         }
                         Err(err) => {
-                            log::error!(
-                                "Failed to upload part {}..{}/{} of file {:?}, retrying: {}",
-                                pos,
-                                end,
-                                file_size,
-                                this.item_id,
-                                err,
+                            attempt += 1;
+                            transfer.report_error(
+                                err.status_code(),
+                                attempt,
+                                true,
+                                format_args!("small file of {} B: {}", file_size, err),
                             );
+                            drop(buf);
                             // Retry
-                            time::sleep(config.retry_delay).await;
+                            this.transfers
+                                .upload_retry_delay(&err, &config, &event_tx)
+                                .await;
                             continue;
                         }
                     }
+                } else {
+                    let sidecars = this.transfers.upload_sidecars.as_ref();
+                    let resumed = match resume {
+                        Some(mut sidecar) => {
+                            let sess = UploadSession::from_upload_url(sidecar.upload_url.clone());
+                            match sess.get_meta(&client).await {
+                                Ok(meta) => {
+                                    if let Some(range) = meta.next_expected_ranges.first() {
+                                        sidecar.next_pos = range.start;
+                                    }
+                                    log::info!(
+                                        "Resuming upload session of {:?} from {}",
+                                        this.item_id,
+                                        sidecar.next_pos,
+                                    );
+                                    Some((sess, sidecar))
+                                }
+                                Err(err) => {
+                                    if err.status_code() == Some(StatusCode::NOT_FOUND) {
+                                        log::info!(
+                                            "Upload session of {:?} expired, restart from zero",
+                                            this.item_id,
+                                        );
+                                    } else {
+                                        log::warn!(
+                                            "Failed to query upload session of {:?}, restart from zero: {}",
+                                            this.item_id,
+                                            err,
+                                        );
+                                    }
+                                    None
+                                }
+                            }
+                        }
+                        None => None,
+                    };
+
+                    let (sess, mut sidecar) = match resumed {
+                        Some(resumed) => resumed,
+                        None => {
+                            // Create upload session.
+                            let sess = match onedrive
+                                .get()
+                                .await
+                                .new_upload_session_with_initial_option(
+                                    ItemLocation::from_id(&this.item_id),
+                                    &initial,
+                                    DriveItemPutOption::new()
+                                        .conflict_behavior(ConflictBehavior::Replace),
+                                )
+                                .await
+                            {
+                                Ok((sess, _)) => sess,
+                                Err(err) => {
+                                    attempt += 1;
+                                    transfer.report_error(
+                                        err.status_code(),
+                                        attempt,
+                                        true,
+                                        format_args!(
+                                            "creating session of {} B: {}",
+                                            file_size, err
+                                        ),
+                                    );
+                                    // Retry
+                                    this.transfers
+                                        .upload_retry_delay(&err, &config, &event_tx)
+                                        .await;
+                                    continue;
+                                }
+                            };
+                            let sidecar = UploadSidecar {
+                                upload_url: sess.upload_url().to_owned(),
+                                next_pos: 0,
+                                file_size,
+                                mtime,
+                                c_tag: this.c_tag.lock().unwrap().clone(),
+                            };
+                            if let Some(sidecars) = sidecars {
+                                let mut guard = this.state.lock().await;
+                                if is_up_to_date(&guard.status) {
+                                    if let Err(err) =
+                                        sidecars.create(&this, &mut guard, &sidecar).await
+                                    {
+                                        log::error!(
+                                            "Failed to save upload session of {:?}: {}",
+                                            this.item_id,
+                                            err,
+                                        );
+                                    }
+                                }
+                            }
+                            (sess, sidecar)
+                        }
+                    };
+
+                    // Upload parts.
+                    let mut pos = sidecar.next_pos;
+                    loop {
+                        this.report_upload_progress(
+                            &transfer,
+                            &mut progress,
+                            &event_tx,
+                            pos,
+                            file_size,
+                        );
+                        let end = file_size.min(pos + config.part_size as u64);
+                        let len = (end - pos) as usize;
+                        let mut buf = this.transfers.upload_buffers.get(len).await;
+                        {
+                            let mut guard = this.state.lock().await;
+                            if !is_up_to_date(&guard.status) {
+                                log::debug!("Upload session of {:?} outdates", this.item_id);
+                                if let Some(sidecars) = sidecars {
+                                    sidecars
+                                        .remove(&this.item_id, Some(sess.upload_url()))
+                                        .await;
+                                }
+                                if let Err(err) = sess.delete(onedrive.get().await.client()).await {
+                                    log::error!(
+                                        "Failed to delete outdated upload session of {:?}: {}",
+                                        this.item_id,
+                                        err,
+                                    );
+                                }
+                                return;
+                            }
+                            assert_eq!(file_size, guard.file_size, "Truncation restarts uploading");
+                            guard.cache_file.seek(SeekFrom::Start(pos)).await.unwrap();
+                            guard.cache_file.read_exact(&mut buf.buf).await.unwrap();
+                        }
+
+                        for limiter in [&this.transfers.upload_limiter, &background_limiter]
+                            .into_iter()
+                            .flatten()
+                        {
+                            limiter.acquire(len as u64).await;
+                        }
+
+                        match sess
+                            .upload_part(buf.buf.split().freeze(), pos..end, file_size, &client)
+                            .await
+                        {
+                            Ok(None) => {
+                                transfer.add_bytes(len as u64);
+                                assert_ne!(end, file_size);
+                                log::debug!(
+                                    "Uploaded part {}..{}/{} of file {:?}",
+                                    pos,
+                                    end,
+                                    file_size,
+                                    this.item_id,
+                                );
+                                pos = end;
+                                if let Some(sidecars) = sidecars {
+                                    sidecar.next_pos = pos;
+                                    if let Err(err) = sidecars.update(&this.item_id, &sidecar).await
+                                    {
+                                        log::warn!(
+                                            "Failed to save upload progress of {:?}: {}",
+                                            this.item_id,
+                                            err,
+                                        );
+                                    }
+                                }
+                            }
+                            Ok(Some(item)) => {
+                                assert_eq!(end, file_size);
+                                if let Some(sidecars) = sidecars {
+                                    sidecars
+                                        .remove(&this.item_id, Some(sess.upload_url()))
+                                        .await;
+                                }
+                                break item;
+                            }
+                            Err(err) => {
+                                attempt += 1;
+                                transfer.report_error(
+                                    err.status_code(),
+                                    attempt,
+                                    true,
+                                    format_args!("part {}..{}/{}: {}", pos, end, file_size, err),
+                                );
+                                // Retry
+                                this.transfers
+                                    .upload_retry_delay(&err, &config, &event_tx)
+                                    .await;
+                                // The part may be partially received. Continue from where the
+                                // server expects, or the same part if it's unknown.
+                                match sess.get_meta(&client).await {
+                                    Ok(meta) => {
+                                        if let Some(range) = meta.next_expected_ranges.first() {
+                                            pos = range.start;
+                                        }
+                                    }
+                                    Err(err)
+                                        if err.status_code() == Some(StatusCode::NOT_FOUND) =>
+                                    {
+                                        log::warn!(
+                                            "Upload session of {:?} expired, restart from zero",
+                                            this.item_id,
+                                        );
+                                        continue 'upload;
+                                    }
+                                    Err(err) => log::debug!(
+                                        "Failed to query upload session of {:?}: {}",
+                                        this.item_id,
+                                        err,
+                                    ),
+                                }
+                                continue;
+                            }
+                        }
+                    }
                 };
 
+                this.report_upload_progress(
+                    &transfer,
+                    &mut progress,
+                    &event_tx,
+                    file_size,
+                    file_size,
+                );
                 let attr = super::InodeAttr::parse_item(&item).expect("Invalid attrs");
                 assert_eq!(item.id.as_ref(), Some(&this.item_id));
                 assert_eq!(attr.size, file_size);
+                let remote_hash = quick_xor_hash_of(&item);
+                let shadow_path = config.shadow_dir.as_ref().map(|dir| {
+                    // Fallback to the item id if the path is not returned.
+                    relative_path_of(&item)
+                        .map(|path| dir.join(path))
+                        .unwrap_or_else(|| dir.join(&this.item_id.0))
+                });
                 let c_tag = item.c_tag.expect("Missing c_tag");
                 log::info!(
                     "Uploaded {:?} ({} B), new c_tag: {:?}",
@@ -1189,7 +3992,14 @@ impl FileCache {
                         FileCacheStatus::Dirty { lock_mtime, .. }
                             if lock_mtime == init_lock_mtime =>
                         {
-                            guard.status = FileCacheStatus::Available;
+                            // Other uploads should succeed as well.
+                            if this.transfers.quota_exceeded.send_replace(false) {
+                                log::info!("Drive space available again, resuming paused uploads");
+                            }
+                            // Saved along with the status if the cache is persistent.
+                            *this.c_tag.lock().unwrap() = c_tag.clone();
+                            *this.remote_hash.lock().unwrap() = remote_hash;
+                            this.set_status(&mut guard, FileCacheStatus::Available);
                         }
                         FileCacheStatus::Invalidated => {
                             log::warn!(
@@ -1204,8 +4014,19 @@ impl FileCache {
                             return;
                         }
                     }
-                    *this.c_tag.lock().unwrap() = c_tag.clone();
                     log::debug!("New c_tag of {:?} saved", this.item_id);
+
+                    if let Some(path) = &shadow_path {
+                        match this.write_shadow(&mut guard, path).await {
+                            Ok(()) => log::debug!("Mirrored {:?} to {:?}", this.item_id, path),
+                            Err(err) => log::error!(
+                                "Failed to mirror {:?} to shadow file {:?}: {}",
+                                this.item_id,
+                                path,
+                                err,
+                            ),
+                        }
+                    }
                 }
 
                 let _ = event_tx
@@ -1224,10 +4045,58 @@ impl FileCache {
     }
 }
 
+impl Drop for DiskCache {
+    fn drop(&mut self) {
+        // Files still in cache are dropped after this, and should be kept for the next run.
+        if let Some(persistent) = &self.persistent {
+            persistent.closing.store(true, Ordering::Release);
+        }
+    }
+}
+
 impl Drop for FileCache {
     fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if matches!(state.status, FileCacheStatus::Dirty { .. }) {
+            self.transfers.dirty_files.fetch_sub(1, Ordering::SeqCst);
+        }
         if let Some(arc) = self.cache_total_size.upgrade() {
-            arc.fetch_sub(self.state.get_mut().file_size, Ordering::Relaxed);
+            arc.fetch_sub(state.file_size, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_segments_cover() {
+        assert_eq!(split_segments(0, 4), Vec::<Range<u64>>::new());
+        assert_eq!(split_segments(8, 4), vec![0..4, 4..8]);
+        assert_eq!(split_segments(10, 4), vec![0..4, 4..8, 8..10]);
+        assert_eq!(split_segments(3, 4), vec![0..3]);
+        // Zero length is treated as one byte, instead of looping forever.
+        assert_eq!(split_segments(2, 0), vec![0..1, 1..2]);
+    }
+
+    #[test]
+    fn upload_progress_monotonic() {
+        const TOTAL: u64 = 100;
+        let start = Instant::now();
+        let mut throttle = ProgressThrottle::default();
+        let mut sent = Vec::new();
+        // Parts committed every 300ms, with a restart from 40 in the middle.
+        for (i, uploaded) in [0, 20, 40, 60, 40, 60, 80, 100].into_iter().enumerate() {
+            let now = start + Duration::from_millis(300 * i as u64);
+            if throttle.should_send(now, uploaded, TOTAL) {
+                sent.push(uploaded);
+            }
         }
+        assert!(sent.windows(2).all(|w| w[0] < w[1]), "{:?}", sent);
+        assert_eq!(sent.first(), Some(&0));
+        assert_eq!(sent.last(), Some(&TOTAL));
+        // Throttled in time.
+        assert!(sent.len() < 8, "{:?}", sent);
     }
 }
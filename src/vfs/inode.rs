@@ -1,5 +1,8 @@
 //! Directory hierarchy and item attributes.
-use crate::vfs::error::{Error, Result};
+use crate::{
+    config::de_duration_sec,
+    vfs::error::{Error, Result},
+};
 use http::StatusCode;
 use indexmap::IndexMap;
 use onedrive_api::{
@@ -8,11 +11,15 @@ use onedrive_api::{
     ConflictBehavior, FileName, ItemId, ItemLocation, OneDrive, Tag,
 };
 use serde::Deserialize;
+use sharded_slab::Slab;
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
+    convert::TryFrom as _,
     sync::Mutex as SyncMutex,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
 
 #[derive(Debug, Clone)]
 pub struct InodeAttr {
@@ -24,10 +31,18 @@ pub struct InodeAttr {
     pub c_tag: Option<Tag>,
     // Whether this file is changed locally and waiting for uploading.
     pub dirty: bool,
+    // Share scope from the `shared` facet, or `None` if not shared.
+    pub shared: Option<String>,
+    // Whether this is a directory without children. It's only filled by `InodePool::get_attr`.
+    pub empty_dir: bool,
+    // ETag and server-side modification time of the last remote state seen, which order
+    // point-in-time fetches of the item against sync.
+    pub e_tag: Option<Tag>,
+    pub remote_mtime: Option<SystemTime>,
 }
 
 impl InodeAttr {
-    pub fn parse_item(item: &DriveItem) -> anyhow::Result<InodeAttr> {
+    pub fn parse_item(item: &DriveItem) -> Result<InodeAttr> {
         use anyhow::Context;
 
         fn parse_time(fs_info: &serde_json::Value, field: &str) -> anyhow::Result<SystemTime> {
@@ -35,7 +50,16 @@ impl InodeAttr {
                 .get(field)
                 .and_then(|v| v.as_str())
                 .with_context(|| format!("Missing {}", field))?;
-            humantime::parse_rfc3339(s).with_context(|| format!("Invalid time: {:?}", s))
+            // An unexpected format should not make the whole item inaccessible.
+            Ok(parse_rfc3339(s).unwrap_or_else(|err| {
+                log::warn!(
+                    "Invalid time {:?} of {}, use UNIX epoch instead: {}",
+                    s,
+                    field,
+                    err
+                );
+                SystemTime::UNIX_EPOCH
+            }))
         }
 
         fn parse_attr(item: &DriveItem) -> anyhow::Result<InodeAttr> {
@@ -54,27 +78,128 @@ impl InodeAttr {
                     Some(item.c_tag.clone().context("Missing c_tag for file")?)
                 },
                 dirty: false,
+                shared: item.shared.as_ref().map(|shared| {
+                    shared
+                        .get("scope")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("unknown")
+                        .to_owned()
+                }),
+                empty_dir: false,
+                e_tag: item.e_tag.clone(),
+                remote_mtime: item
+                    .last_modified_date_time
+                    .as_deref()
+                    .and_then(|s| parse_rfc3339(s).ok()),
             })
         }
 
-        parse_attr(item).with_context(|| format!("Failed to parse item: {:?}", item))
+        parse_attr(item)
+            .with_context(|| format!("Failed to parse item: {:?}", item))
+            .map_err(Error::InvalidMetadata)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct DirEntry {
-    pub item_id: ItemId,
-    pub name: String,
-    pub attr: InodeAttr,
+// `humantime` only accepts UTC times ending with `Z`, so numeric offsets are applied here.
+fn parse_rfc3339(s: &str) -> anyhow::Result<SystemTime> {
+    use anyhow::Context as _;
+
+    let split = s.len().checked_sub(6).filter(|&i| s.is_char_boundary(i));
+    let (utc, offset) = match split.map(|i| s.split_at(i)) {
+        Some((time, offset)) if offset.starts_with(['+', '-']) => (time, offset),
+        _ => return Ok(humantime::parse_rfc3339(s)?),
+    };
+    let (hours, minutes) = offset[1..].split_once(':').context("Invalid offset")?;
+    let secs = hours.parse::<u64>()? * 3600 + minutes.parse::<u64>()? * 60;
+    let time = humantime::parse_rfc3339(&format!("{}Z", utc))?;
+    let delta = Duration::from_secs(secs);
+    // Local time is ahead of UTC for positive offsets.
+    let time = if offset.starts_with('+') {
+        time.checked_sub(delta)
+    } else {
+        time.checked_add(delta)
+    };
+    time.context("Time out of range")
+}
+
+/// The `deleted` facet of an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deletion {
+    /// Moved to the recycle bin, which can be restored.
+    Soft,
+    /// Permanently deleted, or the state is not reported.
+    Permanent,
+}
+
+impl Deletion {
+    pub fn of(item: &DriveItem) -> Option<Self> {
+        let state = item.deleted.as_ref()?.get("state").and_then(|v| v.as_str());
+        Some(match state {
+            Some("softDeleted") => Self::Soft,
+            _ => Self::Permanent,
+        })
+    }
+}
+
+impl std::fmt::Display for Deletion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Soft => "moved to recycle bin",
+            Self::Permanent => "permanently deleted",
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
-pub struct Config {}
+pub struct Config {
+    filename_normalization: Normalization,
+    max_name_length: usize,
+    max_path_length: usize,
+    max_path_depth: usize,
+    case_sensitive_lookup: bool,
+    #[serde(deserialize_with = "de_duration_sec")]
+    negative_ttl: Duration,
+}
+
+/// Unicode normalization form applied to file names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Normalization {
+    Off,
+    Nfc,
+    Nfd,
+}
+
+impl Normalization {
+    fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::Nfc if !is_nfc(name) => Cow::Owned(name.nfc().collect()),
+            Self::Nfd if !is_nfd(name) => Cow::Owned(name.nfd().collect()),
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
 
 pub struct InodePool {
     tree: SyncMutex<InodeTree>,
+    normalization: Normalization,
+    max_name_length: usize,
+    max_path_length: usize,
+    max_path_depth: usize,
+    case_sensitive_lookup: bool,
+    negative_ttl: Duration,
+    dir_handles: Slab<DirHandle>,
+}
+
+/// Children of an open directory, snapshotted when opened.
+/// Offsets of `read_dir` are indices into it, so they are stable even if the directory changes.
+struct DirHandle {
+    parent_id: ItemId,
+    children: Vec<ItemId>,
 }
 
+/// The whole drive tree, fetched by `Tracker` in pages and kept up-to-date by delta updates and
+/// local changes. Listings are served from it, so there is no per-directory cache to expire.
 struct InodeTree {
     // ItemId -> Content, (parent_id, parent_child_idx)
     map: HashMap<ItemId, (Inode, Option<(ItemId, usize)>)>,
@@ -116,14 +241,43 @@ impl InodeTree {
         }
     }
 
+    // Get the length in characters of the full path of an item, excluding the leading `/`.
+    // Ancestors deeper than `max_depth` fail with `PathLoop`, which might be a cycle of
+    // inconsistent moves from the remote side.
+    fn path_length(&self, id: &ItemId, max_depth: usize) -> Result<usize> {
+        let mut len = 0;
+        let mut depth = 0;
+        let mut cur = id;
+        while let Some((parent_id, child_idx)) = &self.map.get(cur).ok_or(Error::NotFound)?.1 {
+            depth += 1;
+            if max_depth < depth {
+                return Err(Error::PathLoop);
+            }
+            let children = self.get(parent_id).ok_or(Error::NotFound)?.children()?;
+            let (name, _) = children.get_index(*child_idx).ok_or(Error::NotFound)?;
+            // Plus the separator.
+            len += name.chars().count() + 1;
+            cur = parent_id;
+        }
+        Ok(len.saturating_sub(1))
+    }
+
     // Set parent of an existing item, or panic if source item or parent item or does not exists.
     fn set_parent(&mut self, item_id: &ItemId, new_parent: Option<(ItemId, String)>) {
         // Detach from old parent.
         if let Some((parent_id, child_idx)) =
             self.map.get_mut(item_id).expect("Item not exists").1.take()
         {
-            let children = self.get_mut(&parent_id).unwrap().children_mut().unwrap();
-            children.swap_remove_index(child_idx);
+            let (children, folded_children) =
+                self.get_mut(&parent_id).unwrap().children_mut().unwrap();
+            let (name, _) = children.swap_remove_index(child_idx).unwrap();
+            let key = fold_case(&name);
+            if let Some(ids) = folded_children.get_mut(&key) {
+                ids.retain(|id| id != item_id);
+                if ids.is_empty() {
+                    folded_children.remove(&key);
+                }
+            }
             if child_idx < children.len() {
                 // Previous last child is swapped to a `child_idx`. Maintain parent reference.
                 let swapped_child_item_id = children[child_idx].clone();
@@ -135,7 +289,11 @@ impl InodeTree {
         // Set a new parent.
         if let Some((new_parent_id, child_name)) = new_parent {
             let (inode, _) = self.map.get_mut(&new_parent_id).expect("Item not exists");
-            let children = inode.children_mut().unwrap();
+            let (children, folded_children) = inode.children_mut().unwrap();
+            folded_children
+                .entry(fold_case(&child_name))
+                .or_default()
+                .push(item_id.clone());
             let (child_idx, old) = children.insert_full(child_name, item_id.clone());
             assert!(old.is_none(), "Duplicated name");
             assert_eq!(child_idx, children.len() - 1);
@@ -152,6 +310,7 @@ enum Inode {
     Dir {
         attr: InodeAttr,
         children: DirChildren,
+        folded_children: FoldedChildren,
     },
 }
 
@@ -161,6 +320,7 @@ impl Inode {
             Self::Dir {
                 attr,
                 children: DirChildren::new(),
+                folded_children: FoldedChildren::new(),
             }
         } else {
             Self::File { attr }
@@ -191,9 +351,22 @@ impl Inode {
         }
     }
 
-    fn children_mut(&mut self) -> Result<&mut DirChildren> {
+    fn children_mut(&mut self) -> Result<(&mut DirChildren, &mut FoldedChildren)> {
         match self {
-            Inode::Dir { children, .. } => Ok(children),
+            Inode::Dir {
+                children,
+                folded_children,
+                ..
+            } => Ok((children, folded_children)),
+            Inode::File { .. } => Err(Error::NotADirectory),
+        }
+    }
+
+    fn folded_children(&self) -> Result<&FoldedChildren> {
+        match self {
+            Inode::Dir {
+                folded_children, ..
+            } => Ok(folded_children),
             Inode::File { .. } => Err(Error::NotADirectory),
         }
     }
@@ -202,6 +375,14 @@ impl Inode {
 // Child name -> Child item id.
 type DirChildren = IndexMap<String, ItemId>;
 
+// Lowercase child name -> Child item ids, for case-insensitive lookups.
+// Names may collide after folding, so all of them are kept.
+type FoldedChildren = HashMap<String, Vec<ItemId>>;
+
+fn fold_case(name: &str) -> String {
+    name.chars().flat_map(char::to_lowercase).collect()
+}
+
 impl InodePool {
     pub const SYNC_SELECT_FIELDS: &'static [DriveItemField] = &[
         // Basic hierarchy information.
@@ -217,50 +398,215 @@ impl InodePool {
         DriveItemField::file_system_info,
         DriveItemField::folder,
         DriveItemField::c_tag,
+        DriveItemField::shared,
+        // Ordering of remote states.
+        DriveItemField::e_tag,
+        DriveItemField::last_modified_date_time,
     ];
 
-    pub fn new(_config: Config) -> Self {
+    pub fn new(config: Config) -> Self {
         Self {
             tree: SyncMutex::new(InodeTree::new()),
+            normalization: config.filename_normalization,
+            max_name_length: config.max_name_length,
+            max_path_length: config.max_path_length,
+            max_path_depth: config.max_path_depth,
+            case_sensitive_lookup: config.case_sensitive_lookup,
+            negative_ttl: config.negative_ttl,
+            dir_handles: Slab::new(),
+        }
+    }
+
+    /// Check the length in bytes of a single path component.
+    pub fn check_name_length(&self, name: &str) -> Result<()> {
+        if self.max_name_length < name.len() {
+            return Err(Error::NameTooLong);
+        }
+        Ok(())
+    }
+
+    /// Check the length in characters of the full path of a new child.
+    pub fn check_path_length(&self, parent_id: &ItemId, name: &FileName) -> Result<()> {
+        let tree = self.tree.lock().unwrap();
+        let parent_len = tree.path_length(parent_id, self.max_path_depth)?;
+        if self.max_path_length < parent_len + 1 + name.as_str().chars().count() {
+            return Err(Error::NameTooLong);
         }
+        Ok(())
+    }
+
+    /// Normalize a file name so that different Unicode forms of the same name compare equal.
+    /// Names passed into other methods of the pool should be already normalized.
+    pub fn normalize_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        self.normalization.apply(name)
     }
 
     /// Get attribute of an item.
+    ///
+    /// Attributes are always served from the tree kept up-to-date by delta sync, so there is no
+    /// per-item fetch on expiry, nor conditional requests needed for it.
     pub fn get_attr(&self, item_id: &ItemId) -> Result<InodeAttr> {
         let tree = self.tree.lock().unwrap();
-        Ok(tree.get(item_id).ok_or(Error::NotFound)?.attr().clone())
+        let inode = tree.get(item_id).ok_or(Error::NotFound)?;
+        Ok(InodeAttr {
+            empty_dir: matches!(inode, Inode::Dir { children, .. } if children.is_empty()),
+            ..inode.attr().clone()
+        })
+    }
+
+    /// Get the name of an item, or `None` for the root or unknown items.
+    pub fn name_of(&self, item_id: &ItemId) -> Option<String> {
+        let tree = self.tree.lock().unwrap();
+        let (parent_id, child_idx) = tree.map.get(item_id)?.1.as_ref()?;
+        let children = tree.get(parent_id)?.children().ok()?;
+        let (name, _) = children.get_index(*child_idx)?;
+        Some(name.clone())
+    }
+
+    /// How long the kernel may cache a failed lookup. Zero if disabled.
+    pub fn negative_ttl(&self) -> Duration {
+        self.negative_ttl
     }
 
     /// Lookup a child by name of an directory item.
+    /// If `case_sensitive_lookup` is not set, it falls back to a case-insensitive match as OneDrive does.
+    /// The whole tree is kept in memory by sync, so it never costs a network round-trip.
     pub fn lookup(&self, parent_id: &ItemId, child_name: &FileName) -> Result<ItemId> {
         let tree = self.tree.lock().unwrap();
-        let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
-        children
-            .get(child_name.as_str())
+        let parent = tree.get(parent_id).ok_or(Error::NotFound)?;
+        if let Some(id) = parent.children()?.get(child_name.as_str()) {
+            return Ok(id.clone());
+        }
+        if self.case_sensitive_lookup {
+            return Err(Error::NotFound);
+        }
+        parent
+            .folded_children()?
+            .get(&fold_case(child_name.as_str()))
+            .and_then(|ids| ids.first())
             .cloned()
             .ok_or(Error::NotFound)
     }
 
-    /// Read entries of a directory.
-    pub fn read_dir(&self, parent_id: &ItemId, offset: u64, count: usize) -> Result<Vec<DirEntry>> {
+    /// Get the parent and the index in it of an item.
+    pub fn position(&self, item_id: &ItemId) -> Option<(ItemId, usize)> {
+        self.tree.lock().unwrap().map.get(item_id)?.1.clone()
+    }
+
+    /// Get at most `count` files following the `idx`-th child of a directory.
+    pub fn next_files(&self, parent_id: &ItemId, idx: usize, count: usize) -> Vec<ItemId> {
         let tree = self.tree.lock().unwrap();
-        let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
-
-        let mut entries = Vec::with_capacity(count);
-        let l = (offset as usize).min(children.len());
-        let r = (l + count).min(children.len());
-        for i in l..r {
-            let (name, child_id) = children.get_index(i).unwrap();
-            let child_attr = tree.get(child_id).unwrap().attr();
-            entries.push(DirEntry {
-                name: name.clone(),
-                item_id: child_id.clone(),
-                attr: child_attr.clone(),
-            });
+        let children = match tree.get(parent_id).map(|inode| inode.children()) {
+            Some(Ok(children)) => children,
+            _ => return Vec::new(),
+        };
+        children
+            .values()
+            .skip(idx + 1)
+            .filter(|id| !tree.get(id).unwrap().attr().is_directory)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    /// Get files in a directory changed locally, ordered by their modification time.
+    pub fn dirty_files(&self, parent_id: &ItemId) -> Vec<ItemId> {
+        let tree = self.tree.lock().unwrap();
+        let children = match tree.get(parent_id).map(|inode| inode.children()) {
+            Some(Ok(children)) => children,
+            _ => return Vec::new(),
+        };
+        let mut files = children
+            .values()
+            .filter_map(|id| {
+                let attr = tree.get(id).unwrap().attr();
+                (attr.dirty && !attr.is_directory).then(|| (attr.mtime, id.clone()))
+            })
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(mtime, _)| *mtime);
+        files.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Get files no larger than `max_size` in a directory,
+    /// or `None` if it has more than `max_children` children.
+    pub fn small_files(
+        &self,
+        parent_id: &ItemId,
+        max_size: u64,
+        max_children: usize,
+    ) -> Option<Vec<ItemId>> {
+        let tree = self.tree.lock().unwrap();
+        let children = tree.get(parent_id)?.children().ok()?;
+        if max_children < children.len() {
+            return None;
+        }
+        let ids = children
+            .values()
+            .filter(|id| {
+                let attr = tree.get(id).unwrap().attr();
+                !attr.is_directory && attr.size <= max_size
+            })
+            .cloned()
+            .collect();
+        Some(ids)
+    }
+
+    /// Open a directory and return the handle for `read_dir`.
+    /// Children are snapshotted from the synced tree, so listing never waits for the network,
+    /// and there is nothing to prefetch on the lookup of the parent.
+    pub fn open_dir(&self, item_id: &ItemId) -> Result<u64> {
+        let tree = self.tree.lock().unwrap();
+        let children = tree.get(item_id).ok_or(Error::NotFound)?.children()?;
+        let handle = DirHandle {
+            parent_id: item_id.clone(),
+            children: children.values().cloned().collect(),
+        };
+        let key = self.dir_handles.insert(handle).expect("Pool is full");
+        Ok(u64::try_from(key).unwrap())
+    }
+
+    pub fn close_dir(&self, fh: u64) -> Result<()> {
+        if self.dir_handles.remove(usize::try_from(fh).unwrap()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidHandle(fh))
+        }
+    }
+
+    /// Read entries of an open directory starting from `offset`, feeding each of them to `f`
+    /// with the offset of the next entry. It stops once `f` returns `true`.
+    /// Children removed or moved away since the directory is opened are skipped.
+    pub fn read_dir(
+        &self,
+        fh: u64,
+        offset: u64,
+        mut f: impl FnMut(u64, &str, &InodeAttr) -> bool,
+    ) -> Result<()> {
+        let handle = self
+            .dir_handles
+            .get(usize::try_from(fh).unwrap())
+            .ok_or(Error::InvalidHandle(fh))?;
+        let tree = self.tree.lock().unwrap();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        for (idx, child_id) in handle.children.iter().enumerate().skip(start) {
+            let (inode, child_idx) = match tree.map.get(child_id) {
+                Some((inode, Some((parent_id, child_idx)))) if *parent_id == handle.parent_id => {
+                    (inode, *child_idx)
+                }
+                _ => continue,
+            };
+            let children = tree.get(&handle.parent_id).unwrap().children().unwrap();
+            let (name, _) = children.get_index(child_idx).unwrap();
+            if f(u64::try_from(idx).unwrap() + 1, name, inode.attr()) {
+                break;
+            }
         }
-        Ok(entries)
+        Ok(())
     }
 
+    /// Create a directory. It's inserted into the tree directly, which is also what listings and
+    /// lookups are served from, so there is no directory cache to invalidate. A name taken on
+    /// remote side but not yet synced fails with `409 Conflict`, as `FileExists`.
     pub async fn create_dir(
         &self,
         parent_id: &ItemId,
@@ -282,7 +628,7 @@ impl InodePool {
                 DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Fail),
             )
             .await?;
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attrs");
+        let attr = InodeAttr::parse_item(&item)?;
         let id = item.id.expect("Missing id");
 
         let mut tree = self.tree.lock().unwrap();
@@ -292,12 +638,15 @@ impl InodePool {
         Ok((id, attr))
     }
 
+    /// Move an item, replacing the existing file at the destination unless `no_replace` is set.
+    /// Returns the id of the replaced item.
     pub async fn rename(
         &self,
         old_parent_id: &ItemId,
         old_name: &FileName,
         new_parent_id: &ItemId,
         new_name: &FileName,
+        no_replace: bool,
         onedrive: &OneDrive,
     ) -> Result<Option<ItemId>> {
         let mut replaced_item_id = None;
@@ -306,6 +655,9 @@ impl InodePool {
             let old_children = tree.get(old_parent_id).ok_or(Error::NotFound)?.children()?;
             let new_children = tree.get(new_parent_id).ok_or(Error::NotFound)?.children()?;
             if let Some(id) = new_children.get(new_name.as_str()) {
+                if no_replace {
+                    return Err(Error::FileExists);
+                }
                 replaced_item_id = Some(id.clone());
                 let attr = tree.get(id).unwrap().attr();
                 if attr.is_directory {
@@ -330,7 +682,12 @@ impl InodePool {
                 ItemLocation::from_id(&item_id),
                 ItemLocation::from_id(new_parent_id),
                 Some(new_name),
-                DriveItemPutOption::new().conflict_behavior(ConflictBehavior::Replace),
+                // The destination may be created on remote side but not synced yet.
+                DriveItemPutOption::new().conflict_behavior(if no_replace {
+                    ConflictBehavior::Fail
+                } else {
+                    ConflictBehavior::Replace
+                }),
             )
             .await
         {
@@ -366,13 +723,15 @@ impl InodePool {
         Ok(replaced_item_id)
     }
 
+    /// Remove an item, locally and on remote side. Return the removed item id.
+    /// Dirty files are rejected, so no running upload would recreate it.
     pub async fn remove(
         &self,
         parent_id: &ItemId,
         name: &FileName,
         directory: bool,
         onedrive: &OneDrive,
-    ) -> Result<()> {
+    ) -> Result<ItemId> {
         let item_id = {
             let tree = self.tree.lock().unwrap();
             let children = tree.get(parent_id).ok_or(Error::NotFound)?.children()?;
@@ -393,7 +752,37 @@ impl InodePool {
         onedrive.delete(ItemLocation::from_id(&item_id)).await?;
 
         self.tree.lock().unwrap().remove_item(&item_id);
-        Ok(())
+        Ok(item_id)
+    }
+
+    /// Apply a point-in-time fetch of an existing item, eg. moved on remote side but not synced
+    /// yet. It's skipped unless it's newer than the state in the tree.
+    /// Return whether it's applied.
+    pub fn reconcile_item(&self, item: &DriveItem) -> bool {
+        let item_id = match &item.id {
+            Some(id) => id,
+            None => return false,
+        };
+        let newer = {
+            let tree = self.tree.lock().unwrap();
+            let attr = match tree.get(item_id) {
+                Some(inode) => inode.attr(),
+                // Unknown items are inserted by sync in order.
+                None => return false,
+            };
+            let new_mtime = item
+                .last_modified_date_time
+                .as_deref()
+                .and_then(|s| parse_rfc3339(s).ok());
+            item.e_tag.is_some()
+                && item.e_tag != attr.e_tag
+                && matches!((new_mtime, attr.remote_mtime), (Some(new), Some(old)) if old < new)
+        };
+        if newer {
+            log::debug!("Reconcile item {:?} from fetched metadata", item_id);
+            self.sync_items(std::slice::from_ref(item));
+        }
+        newer
     }
 
     /// Update attribute of an item. Return updated attribute.
@@ -438,7 +827,7 @@ impl InodePool {
         let item = onedrive
             .update_item_with_option(ItemLocation::from_id(item_id), &patch, opt)
             .await?;
-        let attr = InodeAttr::parse_item(&item).expect("Invalid attr");
+        let attr = InodeAttr::parse_item(&item)?;
         log::debug!(
             "Set attribute of {:?}: mtime -> {}",
             item_id,
@@ -465,13 +854,13 @@ impl InodePool {
             let item_id = item.id.as_ref().expect("Missing id");
 
             // Remove an existing item.
-            if item.deleted.is_some() {
+            if let Some(deletion) = Deletion::of(item) {
                 if tree.get(item_id).is_some() {
                     if item.folder.is_some() {
-                        log::debug!("Mark remove for directory {:?}", item_id);
+                        log::debug!("Mark remove for directory {:?} ({})", item_id, deletion);
                         dir_marked_deleted.insert(item_id);
                     } else {
-                        log::debug!("Remove file {:?}", item_id);
+                        log::debug!("Remove file {:?} ({})", item_id, deletion);
                         tree.remove_item(item_id);
                     }
                 }
@@ -514,20 +903,54 @@ impl InodePool {
                 // Insert a new item.
                 None => {
                     log::debug!("Insert item {:?}", item_id);
-                    let attr = InodeAttr::parse_item(item).expect("Invalid attrs");
+                    let attr = match InodeAttr::parse_item(item) {
+                        Ok(attr) => attr,
+                        Err(err) => {
+                            log::warn!("Skip invalid item {:?} in sync: {}", item_id, err);
+                            continue;
+                        }
+                    };
                     tree.insert_item(item_id.clone(), attr);
                 }
                 // Update an existing item.
                 Some(inode) => {
                     log::debug!("Update item {:?}", item_id);
-                    let attr = InodeAttr::parse_item(item).expect("Invalid attrs");
+                    let attr = match InodeAttr::parse_item(item) {
+                        Ok(attr) => attr,
+                        Err(err) => {
+                            log::warn!("Skip invalid item {:?} in sync: {}", item_id, err);
+                            continue;
+                        }
+                    };
+                    // The tree may already have a newer state from `reconcile_item`.
+                    if let (Some(new), Some(old)) = (attr.remote_mtime, inode.attr().remote_mtime) {
+                        if new < old {
+                            log::debug!("Skip outdated item {:?} in sync", item_id);
+                            continue;
+                        }
+                    }
                     inode.set_attr(attr);
                 }
             }
 
             // Update parent for non-root items.
             if let Some(parent_id) = parent_id {
-                let name = item.name.clone().expect("Missing name");
+                let orig_name = item.name.as_deref().expect("Missing name");
+                let mut name = self.normalization.apply(orig_name).into_owned();
+                // OneDrive compares names byte-exact, so siblings may differ only in their
+                // Unicode forms. Keep the original name for the later one instead of colliding.
+                let collided = tree
+                    .get(&parent_id)
+                    .and_then(|parent| parent.children().ok()?.get(&name))
+                    .is_some_and(|id| id != item_id);
+                if collided {
+                    log::warn!(
+                        "Name {:?} of {:?} collides with a sibling after normalization, keep it as is",
+                        orig_name,
+                        item_id,
+                    );
+                    name = orig_name.to_owned();
+                }
                 tree.set_parent(item_id, Some((parent_id, name)));
             }
         }
@@ -545,3 +968,124 @@ impl InodePool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(normalization: &str, case_sensitive_lookup: bool) -> InodePool {
+        InodePool::new(
+            serde_json::from_value(serde_json::json!({
+                "filename_normalization": normalization,
+                "max_name_length": 255,
+                "max_path_length": 400,
+                "max_path_depth": 200,
+                "case_sensitive_lookup": case_sensitive_lookup,
+                "negative_ttl": 0,
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn item(id: &str, parent_id: Option<&str>, name: &str, is_directory: bool) -> DriveItem {
+        let mut item: DriveItem = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": name,
+            "size": 0,
+            "fileSystemInfo": {
+                "createdDateTime": "2020-01-01T00:00:00Z",
+                "lastModifiedDateTime": "2020-01-01T00:00:00Z",
+            },
+        }))
+        .unwrap();
+        match parent_id {
+            Some(parent_id) => {
+                item.parent_reference = Some(Box::new(serde_json::json!({ "id": parent_id })))
+            }
+            None => item.root = Some(Box::new(serde_json::json!({}))),
+        }
+        if is_directory {
+            item.folder = Some(Box::new(serde_json::json!({})));
+        } else {
+            item.file = Some(Box::new(serde_json::json!({})));
+            item.c_tag = Some(Tag("ctag".to_owned()));
+        }
+        item
+    }
+
+    fn name(s: &str) -> &FileName {
+        FileName::new(s).unwrap()
+    }
+
+    #[test]
+    fn parse_rfc3339_offsets() {
+        let utc = parse_rfc3339("2020-01-01T00:00:00Z").unwrap();
+        assert_eq!(parse_rfc3339("2020-01-01T08:00:00+08:00").unwrap(), utc);
+        assert_eq!(parse_rfc3339("2019-12-31T18:30:00-05:30").unwrap(), utc);
+        assert_eq!(
+            parse_rfc3339("2020-01-01T00:00:00.5Z").unwrap(),
+            utc + Duration::from_millis(500),
+        );
+        assert!(parse_rfc3339("2020-01-01T00:00:00+8:00").is_err());
+        assert!(parse_rfc3339("2020-01-01").is_err());
+        assert!(parse_rfc3339("").is_err());
+    }
+
+    #[test]
+    fn normalization() {
+        let composed = "\u{e9}";
+        let decomposed = "e\u{301}";
+        assert_eq!(Normalization::Off.apply(decomposed), decomposed);
+        assert_eq!(Normalization::Nfc.apply(decomposed), composed);
+        assert_eq!(Normalization::Nfd.apply(composed), decomposed);
+        assert!(matches!(
+            Normalization::Nfc.apply(composed),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            Normalization::Nfd.apply(decomposed),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn normalization_collision() {
+        let pool = pool("nfc", true);
+        let root = ItemId("root".to_owned());
+        pool.sync_items(&[
+            item("root", None, "root", true),
+            item("a", Some("root"), "\u{e9}", false),
+            item("b", Some("root"), "e\u{301}", false),
+        ]);
+        assert_eq!(pool.lookup(&root, name("\u{e9}")).unwrap().as_str(), "a");
+        assert_eq!(pool.name_of(&ItemId("b".to_owned())).unwrap(), "e\u{301}");
+    }
+
+    #[test]
+    fn case_insensitive_lookup() {
+        let root = ItemId("root".to_owned());
+        let items = [
+            item("root", None, "root", true),
+            item("a", Some("root"), "Foo", false),
+        ];
+
+        let pool = pool("off", true);
+        pool.sync_items(&items);
+        assert_eq!(pool.lookup(&root, name("Foo")).unwrap().as_str(), "a");
+        assert!(matches!(
+            pool.lookup(&root, name("foo")),
+            Err(Error::NotFound)
+        ));
+
+        let pool = self::pool("off", false);
+        pool.sync_items(&items);
+        assert_eq!(pool.lookup(&root, name("FOO")).unwrap().as_str(), "a");
+        // Folded names follow renames.
+        pool.sync_items(&[item("a", Some("root"), "Bar", false)]);
+        assert!(matches!(
+            pool.lookup(&root, name("foo")),
+            Err(Error::NotFound)
+        ));
+        assert_eq!(pool.lookup(&root, name("bar")).unwrap().as_str(), "a");
+    }
+}
@@ -17,6 +17,15 @@ struct PoolInner {
     map: HashMap<u64, (u64, ItemId)>,
     /// item_id -> ino
     rev_map: HashMap<ItemId, u64>,
+    /// The max number of inodes alive at the same time.
+    peak: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InodeStats {
+    /// Number of inodes referenced by the kernel.
+    pub alive: usize,
+    pub peak: usize,
 }
 
 impl InodeIdPool {
@@ -27,6 +36,7 @@ impl InodeIdPool {
                 inode_counter: root_ino + 1,
                 map: HashMap::new(),
                 rev_map: HashMap::new(),
+                peak: 0,
             }),
             root_ino,
         }
@@ -57,6 +67,7 @@ impl InodeIdPool {
                 inner.inode_counter += 1;
                 inner.map.insert(ino, (1, item_id.clone()));
                 inner.rev_map.insert(item_id.clone(), ino);
+                inner.peak = inner.peak.max(inner.map.len());
                 ino
             }
         }
@@ -73,6 +84,11 @@ impl InodeIdPool {
                 if ent.get_mut().0 == count {
                     let (_, item_id) = ent.remove();
                     assert!(inner.rev_map.remove(&item_id).is_some());
+                    // Give back memory after a burst, eg. enumerating a huge tree.
+                    if inner.map.capacity() > 4 * inner.map.len().max(1024) {
+                        inner.map.shrink_to_fit();
+                        inner.rev_map.shrink_to_fit();
+                    }
                     Ok(true)
                 } else {
                     ent.get_mut().0 -= count;
@@ -82,6 +98,19 @@ impl InodeIdPool {
         }
     }
 
+    pub fn stats(&self) -> InodeStats {
+        let inner = self.inner.lock().unwrap();
+        InodeStats {
+            alive: inner.map.len(),
+            peak: inner.peak,
+        }
+    }
+
+    /// Get the inode of an item, if it's referenced by the kernel.
+    pub fn get_ino(&self, item_id: &ItemId) -> Option<u64> {
+        self.inner.lock().unwrap().rev_map.get(item_id).copied()
+    }
+
     /// Get item id from an existing inode.
     pub fn get_item_id(&self, ino: u64) -> Result<ItemId> {
         Ok(self
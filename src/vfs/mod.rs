@@ -1,39 +1,69 @@
 use crate::login::ManagedOnedrive;
-use onedrive_api::{resource::DriveItem, FileName, ItemLocation, OneDrive};
+use anyhow::Context as _;
+use bytes::Bytes;
+use onedrive_api::{resource::DriveItem, FileName, ItemId, ItemLocation, OneDrive};
 use serde::Deserialize;
 use std::{
+    borrow::Cow,
     ffi::OsStr,
     ops::Deref,
     sync::{Arc, Weak},
     time::{Duration, SystemTime},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 
+mod cache_backend;
 pub mod error;
 mod file;
 mod inode;
 mod inode_id;
+mod prefetch;
+mod quick_xor;
+pub mod rate_limit;
 mod statfs;
+mod status;
 mod tracker;
+mod versions;
 
 pub use error::{Error, Result};
-pub use inode::{DirEntry, InodeAttr};
+pub use inode::InodeAttr;
 pub use statfs::StatfsData;
 
+/// Extended attribute of the share scope, or "none" if not shared.
+const XATTR_SHARED: &str = "user.onedrive.shared";
+/// Extended attribute of the version history of a file. See `versions::render_versions`.
+const XATTR_VERSIONS: &str = "user.onedrive.versions";
+/// Extended attribute which waits for the file to be fully cached, then gives "yes",
+/// or "no" if it's not in cache.
+const XATTR_CACHED: &str = "user.onedrive.cached";
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     statfs: statfs::Config,
     inode: inode::Config,
     file: file::Config,
     tracker: tracker::Config,
+    status: status::Config,
 }
 
 #[derive(Debug)]
 pub enum UpdateEvent {
     /// Batch update from old states.
     BatchUpdate(Vec<DriveItem>),
+    /// A point-in-time fetch of an item, applied only if it's newer than the synced state.
+    ReconcileItem(Box<DriveItem>),
     /// Update attribute of a single file due to modification.
     UpdateFile(file::UpdatedFileAttr),
+    /// The connection to remote side is restored after failures.
+    Reconnected,
+    /// Uploads failed since the drive is full, and are paused until space frees up.
+    QuotaExceeded,
+    /// `uploaded` of `total` bytes of an upload are committed. It's throttled in time.
+    UploadProgress {
+        item_id: ItemId,
+        uploaded: u64,
+        total: u64,
+    },
 }
 
 pub struct Vfs {
@@ -42,7 +72,11 @@ pub struct Vfs {
     inode_pool: inode::InodePool,
     file_pool: file::FilePool,
     tracker: tracker::Tracker,
+    status_file: status::StatusFile,
+    access_tracker: prefetch::AccessTracker,
     onedrive: ManagedOnedrive,
+    /// The id of the drive mounted.
+    drive_id: String,
     readonly: bool,
 }
 
@@ -55,6 +89,7 @@ impl Vfs {
         client: reqwest::Client,
     ) -> anyhow::Result<Arc<Self>> {
         let statfs = statfs::Statfs::new(onedrive.clone(), config.statfs).await?;
+        let drive_id = Self::fetch_drive_id(&*onedrive.get().await).await?;
 
         let (event_tx, event_rx) = mpsc::channel(1);
         let (init_tx, init_rx) = oneshot::channel();
@@ -78,15 +113,20 @@ impl Vfs {
                 event_tx,
                 onedrive.clone(),
                 client.clone(),
+                &drive_id,
                 config.file,
             )?,
             tracker,
+            status_file: status::StatusFile::new(root_ino, config.status),
+            access_tracker: prefetch::AccessTracker::default(),
             onedrive,
+            drive_id,
             readonly,
         });
 
         tokio::task::spawn(Self::sync_thread(Arc::downgrade(&this), event_rx, init_tx));
-        // Wait for initialization.
+        // Wait for initialization. The first sync fetches the whole tree, so every
+        // directory, including the root, can be listed from memory right after mount.
         init_rx.await.expect("Initialization failed");
         Ok(this)
     }
@@ -104,9 +144,25 @@ impl Vfs {
             };
 
             match event {
+                UpdateEvent::ReconcileItem(item) => {
+                    if this.inode_pool.reconcile_item(&item) {
+                        this.file_pool.sync_items(std::slice::from_ref(&item)).await;
+                    }
+                }
                 UpdateEvent::BatchUpdate(updated) => {
                     this.inode_pool.sync_items(&updated);
                     this.file_pool.sync_items(&updated).await;
+                    if this.file_pool.check_consistency() {
+                        let errors = this
+                            .file_pool
+                            .verify_consistency(|id| {
+                                this.inode_pool.get_attr(id).ok().map(|attr| attr.size)
+                            })
+                            .await;
+                        for err in errors {
+                            log::error!("Cache inconsistency: {}", err);
+                        }
+                    }
 
                     if let Some(init_tx) = init_tx.take() {
                         let root_id = updated
@@ -134,14 +190,157 @@ impl Vfs {
                             ..attr
                         });
                 }
+                UpdateEvent::Reconnected => this.file_pool.notify_reconnected(),
+                UpdateEvent::QuotaExceeded => {
+                    let free = this.statfs.statfs().free;
+                    tokio::spawn(Self::quota_watch_thread(Arc::downgrade(&this), free));
+                }
+                UpdateEvent::UploadProgress {
+                    item_id,
+                    uploaded,
+                    total,
+                } => {
+                    log::debug!(
+                        "Upload progress of {:?}: {}/{} ({:.0}%)",
+                        item_id,
+                        uploaded,
+                        total,
+                        if total == 0 {
+                            100.0
+                        } else {
+                            uploaded as f64 * 100.0 / total as f64
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Resume paused uploads once the free space reported by statfs grows above `free`.
+    async fn quota_watch_thread(this: Weak<Self>, free: u64) {
+        const CHECK_PERIOD: Duration = Duration::from_secs(10);
+        loop {
+            tokio::time::sleep(CHECK_PERIOD).await;
+            let this = match this.upgrade() {
+                Some(this) => this,
+                None => return,
+            };
+            if free < this.statfs.statfs().free {
+                this.file_pool.notify_quota_freed();
+                return;
             }
         }
     }
 
+    fn cvt_filename<'a>(&self, name: &'a OsStr) -> Result<NormalizedName<'a>> {
+        let invalid = || Error::InvalidFileName(name.to_owned());
+        let name = self
+            .inode_pool
+            .normalize_name(name.to_str().ok_or_else(invalid)?);
+        FileName::new(&*name).ok_or_else(invalid)?;
+        self.inode_pool.check_name_length(&name)?;
+        Ok(NormalizedName(name))
+    }
+
+    /// Like `cvt_filename`, but also reject names OneDrive refuses to create.
+    ///
+    /// `FileName` only rejects `"*:<>?/\|`, while any other Unicode is accepted by both sides.
+    /// The extra rules are only checked for new names, so that lookups of them simply miss.
+    /// See: https://support.microsoft.com/en-us/office/restrictions-and-limitations-in-onedrive-and-sharepoint-64883a5d-228e-48f5-b3d2-eb39e07630fa
+    fn cvt_new_filename<'a>(&self, name: &'a OsStr) -> Result<NormalizedName<'a>> {
+        let ret = self.cvt_filename(name)?;
+        Self::check_new_filename(&ret)?;
+        Ok(ret)
+    }
+
+    /// The extra rules of `cvt_new_filename` on an already converted name.
+    fn check_new_filename(name: &FileName) -> Result<()> {
+        let s = name.as_str();
+        let stem = s.split('.').next().unwrap_or_default().to_ascii_uppercase();
+        let is_device = matches!(&*stem, "CON" | "PRN" | "AUX" | "NUL")
+            || (stem.len() == 4
+                && (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && stem.as_bytes()[3].is_ascii_digit());
+        if is_device
+            || s == ".lock"
+            || s.eq_ignore_ascii_case("desktop.ini")
+            || s.starts_with("~$")
+            || s.contains("_vti_")
+            || s.starts_with(' ')
+            || s.ends_with(' ')
+        {
+            return Err(Error::InvalidFileName(s.into()));
+        }
+        Ok(())
+    }
+
+    /// The id of the drive mounted, which also identifies the account for the disk cache.
+    async fn fetch_drive_id(onedrive: &OneDrive) -> anyhow::Result<String> {
+        use onedrive_api::{option::ObjectOption, resource::DriveField};
+
+        let drive = onedrive
+            .get_drive_with_option(ObjectOption::new().select(&[DriveField::id]))
+            .await?;
+        Ok(drive.id.context("Missing drive id")?.0)
+    }
+
     async fn onedrive(&self) -> impl Deref<Target = OneDrive> + '_ {
         self.onedrive.get().await
     }
 
+    /// Whether any download or upload is in progress.
+    pub fn is_syncing(&self) -> bool {
+        self.file_pool.is_syncing()
+    }
+
+    /// Subscribe the transitions between syncing and idle state.
+    pub fn subscribe_syncing(&self) -> watch::Receiver<bool> {
+        self.file_pool.subscribe_syncing()
+    }
+
+    /// Whether `ino` is a virtual file whose content is generated on every read,
+    /// and should bypass the page cache.
+    pub fn is_virtual_file(&self, ino: u64) -> bool {
+        ino == status::StatusFile::INO
+    }
+
+    /// Items with open handles, with their inodes if still referenced by the kernel.
+    pub fn open_files(&self) -> Vec<(Option<u64>, ItemId)> {
+        self.file_pool
+            .open_files()
+            .into_iter()
+            .map(|item_id| (self.id_pool.get_ino(&item_id), item_id))
+            .collect()
+    }
+
+    /// Number of files modified locally and not yet uploaded.
+    pub fn dirty_file_count(&self) -> usize {
+        self.file_pool.stats().dirty_files
+    }
+
+    fn render_status(&self) -> String {
+        let mut file = self.file_pool.stats();
+        if let Some(err) = &mut file.last_transfer_error {
+            err.name = self.inode_pool.name_of(&err.item_id);
+        }
+        status::StatusFile::render(&status::Status {
+            syncing: self.is_syncing(),
+            open_files: self.open_files(),
+            file,
+            cache_by_extension: self.file_pool.stats_by_extension(),
+            inodes: self.id_pool.stats(),
+            time_since_last_sync: self.tracker.time_since_last_sync(),
+            last_error: self.tracker.last_error(),
+        })
+    }
+
+    /// How long the kernel may cache a name as missing after a failed lookup, or `None` if it
+    /// shouldn't. Names created locally replace the negative entries in the kernel, while remote
+    /// changes can only appear after the next sync, so it never exceeds `ttl`.
+    pub fn negative_ttl(&self) -> Option<Duration> {
+        Some(self.inode_pool.negative_ttl().min(self.ttl())).filter(|ttl| !ttl.is_zero())
+    }
+
     fn ttl(&self) -> Duration {
         // Use `i64::MAX` to avoid overflowing `libc::time_t`;
         const MAX_TTL: Duration = Duration::from_secs(i64::MAX as u64);
@@ -160,7 +359,11 @@ impl Vfs {
         child_name: &OsStr,
     ) -> Result<(u64, InodeAttr, Duration)> {
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        let child_name = cvt_filename(child_name)?;
+        let child_name = &*self.cvt_filename(child_name)?;
+        if self.status_file.matches(parent_ino, child_name.as_str()) {
+            let attr = self.status_file.attr(&self.render_status());
+            return Ok((status::StatusFile::INO, attr, Duration::ZERO));
+        }
         let id = self.inode_pool.lookup(&parent_id, child_name)?;
         let attr = self.inode_pool.get_attr(&id)?;
         let ino = self.id_pool.acquire_or_alloc(&id);
@@ -169,50 +372,149 @@ impl Vfs {
     }
 
     pub async fn forget(&self, ino: u64, count: u64) -> Result<()> {
+        if self.is_virtual_file(ino) {
+            return Ok(());
+        }
         let freed = self.id_pool.free(ino, count)?;
         log::trace!(target: "vfs::inode", "forget: ino={} count={} freed={}", ino, count, freed);
         Ok(())
     }
 
     pub async fn get_attr(&self, ino: u64) -> Result<(InodeAttr, Duration)> {
+        if self.is_virtual_file(ino) {
+            return Ok((self.status_file.attr(&self.render_status()), Duration::ZERO));
+        }
         let id = self.id_pool.get_item_id(ino)?;
         let attr = self.inode_pool.get_attr(&id)?;
         log::trace!(target: "vfs::inode", "get_attr: id={:?} ino={} attr={:?}", id, ino, attr);
         Ok((attr, self.ttl()))
     }
 
-    // fh is not used for directories.
+    /// Get the value of an extended attribute.
+    pub async fn get_xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let (attr, _) = self.get_attr(ino).await?;
+        let value = if name == XATTR_SHARED {
+            attr.shared.unwrap_or_else(|| "none".to_owned())
+        } else if name == XATTR_VERSIONS && !attr.is_directory && !self.is_virtual_file(ino) {
+            let item_id = self.id_pool.get_item_id(ino)?;
+            versions::render_versions(&*self.onedrive().await, &self.drive_id, &item_id).await?
+        } else if name == XATTR_CACHED && !attr.is_directory && !self.is_virtual_file(ino) {
+            let item_id = self.id_pool.get_item_id(ino)?;
+            let cached = self.file_pool.await_cached(&item_id).await?;
+            (if cached { "yes" } else { "no" }).to_owned()
+        } else {
+            return Err(Error::NoAttribute);
+        };
+        log::trace!(target: "vfs::inode", "get_xattr: ino={} name={:?} value={}", ino, name, value);
+        Ok(value.into_bytes())
+    }
+
+    /// List names of all extended attributes, each terminated by NUL.
+    pub async fn list_xattr(&self, ino: u64) -> Result<Vec<u8>> {
+        let (attr, _) = self.get_attr(ino).await?;
+        let mut names = format!("{}\0", XATTR_SHARED);
+        if !attr.is_directory && !self.is_virtual_file(ino) {
+            for name in [XATTR_VERSIONS, XATTR_CACHED] {
+                names += name;
+                names.push('\0');
+            }
+        }
+        Ok(names.into_bytes())
+    }
+
     pub async fn open_dir(&self, ino: u64) -> Result<u64> {
-        log::trace!(target: "vfs::dir", "open_dir: ino={}", ino);
-        Ok(0)
+        let item_id = self.id_pool.get_item_id(ino)?;
+        let fh = self.inode_pool.open_dir(&item_id)?;
+        log::trace!(target: "vfs::dir", "open_dir: ino={} fh={}", ino, fh);
+        self.warm_small_files(&item_id);
+        Ok(fh)
     }
 
-    // fh is not used for directories.
-    pub async fn close_dir(&self, ino: u64, _fh: u64) -> Result<()> {
-        log::trace!(target: "vfs::dir", "close_dir: ino={}", ino);
+    /// Prefetch small files in a directory being listed, if enabled.
+    fn warm_small_files(&self, dir_id: &ItemId) {
+        // Listing large directories doesn't imply reading all files.
+        const MAX_DIR_ENTRIES: usize = 256;
+
+        let max_size = self.file_pool.warm_small_on_list();
+        if max_size == 0 {
+            return;
+        }
+        if let Some(ids) = self
+            .inode_pool
+            .small_files(dir_id, max_size, MAX_DIR_ENTRIES)
+        {
+            self.file_pool.prefetch(ids);
+        }
+    }
+
+    pub async fn close_dir(&self, ino: u64, fh: u64) -> Result<()> {
+        self.inode_pool.close_dir(fh)?;
+        log::trace!(target: "vfs::dir", "close_dir: ino={} fh={}", ino, fh);
         Ok(())
     }
 
+    /// Feed entries of a directory with the offset of the next entry to `f`,
+    /// until it returns `true`. See `InodePool::read_dir`.
     pub async fn read_dir(
         &self,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: u64,
-        count: usize,
-    ) -> Result<impl AsRef<[DirEntry]>> {
-        let parent_id = self.id_pool.get_item_id(ino)?;
-        let ret = self.inode_pool.read_dir(&parent_id, offset, count)?;
-        log::trace!(target: "vfs::dir", "read_dir: ino={} offset={}", ino, offset);
-        Ok(ret)
+        mut f: impl FnMut(u64, &str, &InodeAttr) -> bool,
+    ) -> Result<()> {
+        // The status file takes the first offset if listed.
+        let skip = u64::from(self.status_file.listed_in(ino));
+        if offset < skip {
+            let attr = self.status_file.attr(&self.render_status());
+            if f(skip, self.status_file.file_name(), &attr) {
+                return Ok(());
+            }
+        }
+        self.inode_pool
+            .read_dir(fh, offset.saturating_sub(skip), |next, name, attr| {
+                f(next + skip, name, attr)
+            })?;
+        log::trace!(target: "vfs::dir", "read_dir: ino={} fh={} offset={}", ino, fh, offset);
+        Ok(())
     }
 
-    pub async fn open_file(&self, ino: u64, write: bool) -> Result<u64> {
+    pub async fn open_file(&self, ino: u64, write: bool, direct: bool) -> Result<u64> {
+        if self.is_virtual_file(ino) {
+            // The handle is unused, since reads are dispatched by the inode.
+            return if write {
+                Err(Error::ReadOnlyFile)
+            } else {
+                Ok(0)
+            };
+        }
         let item_id = self.id_pool.get_item_id(ino)?;
-        let fh = self.file_pool.open(&item_id, write).await?;
+        let name = self.inode_pool.name_of(&item_id);
+        let fh = self
+            .file_pool
+            .open(&item_id, name.as_deref(), write, direct)
+            .await?;
         log::trace!(target: "vfs::file", "open_file: ino={} fh={}", ino, fh);
+        // Direct I/O asks for exactly what is read, without speculation.
+        if !direct {
+            self.prefetch_after(&item_id);
+        }
         Ok(fh)
     }
 
+    /// Prefetch upcoming files in the same directory if it's a sequential traversal.
+    fn prefetch_after(&self, item_id: &ItemId) {
+        let count = self.file_pool.predictive_prefetch();
+        if count == 0 {
+            return;
+        }
+        if let Some((parent_id, idx)) = self.inode_pool.position(item_id) {
+            if self.access_tracker.record(&parent_id, idx) {
+                let next = self.inode_pool.next_files(&parent_id, idx, count);
+                self.file_pool.prefetch(next);
+            }
+        }
+    }
+
     pub async fn open_create_file(
         &self,
         parent_ino: u64,
@@ -221,7 +523,12 @@ impl Vfs {
         exclusive: bool,
     ) -> Result<(u64, u64, InodeAttr, Duration)> {
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        let child_name = cvt_filename(child_name)?;
+        // Reserved names are only rejected when a new item is created, so that existing ones are
+        // still writable.
+        let child_name = &*self.cvt_filename(child_name)?;
+        if self.status_file.matches(parent_ino, child_name.as_str()) {
+            return Err(Error::ReadOnlyFile);
+        }
         if !truncate {
             // FIXME: Not atomic.
             match self.inode_pool.lookup(&parent_id, child_name) {
@@ -231,13 +538,17 @@ impl Vfs {
                     }
                     let attr = self.inode_pool.get_attr(&id)?;
                     let ino = self.id_pool.acquire_or_alloc(&id);
-                    let fh = self.open_file(ino, true).await?;
+                    let fh = self.open_file(ino, true, false).await?;
                     return Ok((ino, fh, attr, self.ttl()));
                 }
                 Err(Error::NotFound) => {}
                 Err(err) => return Err(err),
             }
+            Self::check_new_filename(child_name)?;
+        } else if self.inode_pool.lookup(&parent_id, child_name).is_err() {
+            Self::check_new_filename(child_name)?;
         }
+        self.inode_pool.check_path_length(&parent_id, child_name)?;
         let (fh, item_id, attr) = self
             .file_pool
             .open_create_empty(ItemLocation::child_of_id(&parent_id, child_name))
@@ -249,6 +560,9 @@ impl Vfs {
     }
 
     pub async fn close_file(&self, ino: u64, fh: u64) -> Result<()> {
+        if self.is_virtual_file(ino) {
+            return Ok(());
+        }
         self.file_pool.close(fh).await?;
         log::trace!(target: "vfs::file", "close_file: ino={} fh={}", ino, fh);
         Ok(())
@@ -261,7 +575,16 @@ impl Vfs {
         offset: u64,
         size: usize,
     ) -> Result<impl AsRef<[u8]>> {
-        let ret = self.file_pool.read(fh, offset, size).await?;
+        let ret = if self.is_virtual_file(ino) {
+            let content = Bytes::from(self.render_status());
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(content.len());
+            let end = start.saturating_add(size).min(content.len());
+            content.slice(start..end)
+        } else {
+            self.file_pool.read(fh, offset, size).await?
+        };
         log::trace!(
             target: "vfs::file",
             "read_file: ino={} fh={} offset={} size={} bytes_read={}",
@@ -279,8 +602,12 @@ impl Vfs {
         parent_ino: u64,
         name: &OsStr,
     ) -> Result<(u64, InodeAttr, Duration)> {
-        let name = cvt_filename(name)?;
+        let name = &*self.cvt_new_filename(name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
+        if self.status_file.matches(parent_ino, name.as_str()) {
+            return Err(Error::FileExists);
+        }
+        self.inode_pool.check_path_length(&parent_id, name)?;
         let (id, attr) = self
             .inode_pool
             .create_dir(&parent_id, name, &*self.onedrive().await)
@@ -300,11 +627,14 @@ impl Vfs {
         name: &OsStr,
         new_parent_ino: u64,
         new_name: &OsStr,
+        no_replace: bool,
     ) -> Result<()> {
-        let name = cvt_filename(name)?;
-        let new_name = cvt_filename(new_name)?;
+        let name = &*self.cvt_filename(name)?;
+        let new_name = &*self.cvt_new_filename(new_name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         let new_parent_id = self.id_pool.get_item_id(new_parent_ino)?;
+        self.inode_pool
+            .check_path_length(&new_parent_id, new_name)?;
         let replaced_item_id = self
             .inode_pool
             .rename(
@@ -312,15 +642,13 @@ impl Vfs {
                 name,
                 &new_parent_id,
                 new_name,
+                no_replace,
                 &*self.onedrive().await,
             )
             .await?;
         // If some item is replace, remove it from cache.
         if let Some(id) = replaced_item_id {
-            let mut mock_item = DriveItem::default();
-            mock_item.id = Some(id);
-            mock_item.deleted = Some(Box::new(serde_json::Value::Null));
-            self.file_pool.sync_items(&[mock_item]).await;
+            self.file_pool.remove(&id).await;
         }
         log::trace!(
             target: "vfs::dir",
@@ -332,7 +660,7 @@ impl Vfs {
     }
 
     pub async fn remove_dir(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
-        let name = cvt_filename(name)?;
+        let name = &*self.cvt_filename(name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
         self.inode_pool
             .remove(&parent_id, name, true, &*self.onedrive().await)
@@ -346,15 +674,18 @@ impl Vfs {
     }
 
     pub async fn remove_file(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
-        let name = cvt_filename(name)?;
+        let name = &*self.cvt_filename(name)?;
         let parent_id = self.id_pool.get_item_id(parent_ino)?;
-        self.inode_pool
+        let id = self
+            .inode_pool
             .remove(&parent_id, name, false, &*self.onedrive().await)
             .await?;
+        // Don't wait for the deletion to come back in the next sync.
+        self.file_pool.remove(&id).await;
         log::trace!(
             target: "vfs::dir",
-            "remove_file: parent_id={:?} parent_ino={} name={}",
-            parent_id, parent_ino, name.as_str(),
+            "remove_file: parent_id={:?} parent_ino={} name={} id={:?}",
+            parent_id, parent_ino, name.as_str(), id,
         );
         Ok(())
     }
@@ -376,12 +707,50 @@ impl Vfs {
         Ok(())
     }
 
+    /// Allocate space for a file. Only extending and `FALLOC_FL_KEEP_SIZE` are supported.
+    /// Since space is accounted by file sizes, the latter only checks the handle.
+    pub async fn fallocate(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        len: u64,
+        mode: i32,
+    ) -> Result<()> {
+        self.file_pool.ensure_cached(fh)?;
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(Error::Unsupported);
+        }
+        let item_id = self.id_pool.get_item_id(ino)?;
+        let old_size = self.inode_pool.get_attr(&item_id)?.size;
+        // `FileTooLarge` is `EPERM` for writes, while allocations expect `EFBIG`.
+        let new_size = offset.checked_add(len).ok_or(Error::AllocTooLarge)?;
+        if mode & libc::FALLOC_FL_KEEP_SIZE == 0 && old_size < new_size {
+            let (attr, _) =
+                self.set_attr(ino, Some(new_size), None)
+                    .await
+                    .map_err(|err| match err {
+                        Error::FileTooLarge => Error::AllocTooLarge,
+                        err => err,
+                    })?;
+            log::trace!(
+                target: "vfs::file",
+                "fallocate: ino={} fh={} offset={} len={} new_attr={:?}",
+                ino, fh, offset, len, attr,
+            );
+        }
+        Ok(())
+    }
+
     pub async fn set_attr(
         &self,
         ino: u64,
         size: Option<u64>,
         mtime: Option<SystemTime>,
     ) -> Result<(InodeAttr, Duration)> {
+        if self.is_virtual_file(ino) {
+            return Err(Error::ReadOnlyFile);
+        }
         let item_id = self.id_pool.get_item_id(ino)?;
         let old_attr = self.inode_pool.get_attr(&item_id)?;
         if size.is_some() && old_attr.is_directory {
@@ -421,7 +790,7 @@ impl Vfs {
     }
 
     pub async fn sync_file(&self, ino: u64) -> Result<()> {
-        if self.readonly {
+        if self.readonly || self.is_virtual_file(ino) {
             return Ok(());
         }
         let item_id = self.id_pool.get_item_id(ino)?;
@@ -433,10 +802,31 @@ impl Vfs {
         );
         Ok(())
     }
+
+    /// Flush all files changed in a directory, in the order they were modified.
+    pub async fn sync_dir(&self, ino: u64) -> Result<()> {
+        if self.readonly {
+            return Ok(());
+        }
+        let item_id = self.id_pool.get_item_id(ino)?;
+        let files = self.inode_pool.dirty_files(&item_id);
+        self.file_pool.flush_ordered(&files).await?;
+        log::trace!(
+            target: "vfs::dir",
+            "sync_dir: ino={} id={:?} files={:?}",
+            ino, item_id, files,
+        );
+        Ok(())
+    }
 }
 
-fn cvt_filename(name: &OsStr) -> Result<&FileName> {
-    name.to_str()
-        .and_then(FileName::new)
-        .ok_or_else(|| Error::InvalidFileName(name.to_owned()))
+/// A validated and normalized file name.
+struct NormalizedName<'a>(Cow<'a, str>);
+
+impl Deref for NormalizedName<'_> {
+    type Target = FileName;
+
+    fn deref(&self) -> &FileName {
+        FileName::new(&*self.0).expect("Validated")
+    }
 }
@@ -0,0 +1,35 @@
+//! Detection of sequential access patterns for predictive prefetch.
+use onedrive_api::ItemId;
+use std::sync::Mutex as SyncMutex;
+
+/// Number of consecutive forward opens in a directory to be considered as a sequential traversal.
+const MIN_STREAK: usize = 2;
+
+#[derive(Default)]
+pub struct AccessTracker {
+    /// The parent and the index of the last opened file, and the length of the forward streak.
+    last: SyncMutex<Option<(ItemId, usize, usize)>>,
+}
+
+impl AccessTracker {
+    /// Record an open of the `idx`-th child of `parent_id`.
+    /// Returns whether it is a part of a sequential traversal.
+    pub fn record(&self, parent_id: &ItemId, idx: usize) -> bool {
+        let mut last = self.last.lock().unwrap();
+        let streak = match &*last {
+            Some((last_parent, last_idx, streak))
+                if last_parent == parent_id && *last_idx < idx =>
+            {
+                streak + 1
+            }
+            Some((last_parent, last_idx, streak))
+                if last_parent == parent_id && *last_idx == idx =>
+            {
+                *streak
+            }
+            _ => 0,
+        };
+        *last = Some((parent_id.clone(), idx, streak));
+        MIN_STREAK <= streak
+    }
+}
@@ -0,0 +1,74 @@
+//! QuickXorHash, the content hash provided by OneDrive for all files.
+//! See: https://learn.microsoft.com/en-us/onedrive/developer/code-snippets/quickxorhash
+use base64::Engine as _;
+
+const WIDTH_IN_BYTES: usize = 20;
+const SHIFT: usize = 11;
+
+#[derive(Debug, Clone, Default)]
+pub struct QuickXorHash {
+    /// The 160-bit cyclic state in little-endian.
+    state: [u8; WIDTH_IN_BYTES],
+    len: u64,
+}
+
+impl QuickXorHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        const WIDTH_IN_BITS: usize = WIDTH_IN_BYTES * 8;
+        let mut bit = (self.len % WIDTH_IN_BITS as u64) as usize * SHIFT % WIDTH_IN_BITS;
+        for &b in data {
+            let v = u16::from(b) << (bit % 8);
+            let idx = bit / 8;
+            self.state[idx] ^= v as u8;
+            self.state[(idx + 1) % WIDTH_IN_BYTES] ^= (v >> 8) as u8;
+            bit = (bit + SHIFT) % WIDTH_IN_BITS;
+        }
+        self.len += data.len() as u64;
+    }
+
+    /// Get the base64-encoded digest, in the same format as `quickXorHash` from the API.
+    pub fn finish(&self) -> String {
+        let mut digest = self.state;
+        for (x, y) in digest[WIDTH_IN_BYTES - 8..]
+            .iter_mut()
+            .zip(self.len.to_le_bytes())
+        {
+            *x ^= y;
+        }
+        base64::engine::general_purpose::STANDARD.encode(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(chunks: &[&[u8]]) -> String {
+        let mut hasher = QuickXorHash::new();
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn known_vectors() {
+        // Computed by the reference implementation.
+        assert_eq!(hash(&[]), "AAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+        assert_eq!(hash(&[b"Hello, World!"]), "SCgDG9jwBhaA4ApvnQMbyBACAAA=");
+        let data = (0..1000u32).map(|i| (i * 7 + 3) as u8).collect::<Vec<_>>();
+        assert_eq!(hash(&[&data]), "dgD8j0n8sM0aPE5CUJ8tqmilX/E=");
+    }
+
+    #[test]
+    fn chunked_update() {
+        let data = (0..1000u32).map(|i| (i * 7 + 3) as u8).collect::<Vec<_>>();
+        let (a, rest) = data.split_at(1);
+        let (b, c) = rest.split_at(332);
+        assert_eq!(hash(&[a, b, c]), hash(&[&data]));
+    }
+}
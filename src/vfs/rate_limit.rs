@@ -0,0 +1,73 @@
+//! Rate limiter of bandwidth or requests, shared between transfers.
+use std::{
+    sync::Mutex as SyncMutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// The instant when all previously acquired bytes are transferred.
+    next_free: SyncMutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Create a limiter with the given rate. Returns `None` for zero, which means unlimited.
+    pub fn new(bytes_per_sec: u64) -> Option<Self> {
+        if bytes_per_sec == 0 {
+            return None;
+        }
+        Some(Self {
+            bytes_per_sec,
+            next_free: SyncMutex::new(Instant::now()),
+        })
+    }
+
+    /// Wait until `bytes` can be transferred.
+    ///
+    /// Acquirers are served in order. No lock is held during waiting, so a cancelled
+    /// acquisition never blocks others.
+    pub async fn acquire(&self, bytes: u64) {
+        let start = {
+            let mut next_free = self.next_free.lock().unwrap();
+            // Idle time is not accumulated, so there is no burst after a long idle.
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        assert!(RateLimiter::new(0).is_none());
+    }
+
+    #[tokio::test]
+    async fn limit_rate() {
+        let limiter = RateLimiter::new(1000).unwrap();
+        let start = Instant::now();
+        // The first acquisition is served immediately.
+        limiter.acquire(100).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        for _ in 0..3 {
+            limiter.acquire(100).await;
+        }
+        assert!(Duration::from_millis(300) <= start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn no_burst_after_idle() {
+        let limiter = RateLimiter::new(1000).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let start = Instant::now();
+        limiter.acquire(100).await;
+        limiter.acquire(100).await;
+        assert!(Duration::from_millis(100) <= start.elapsed());
+    }
+}
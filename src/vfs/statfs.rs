@@ -21,12 +21,38 @@ pub struct Config {
     refresh_period: Duration,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StatfsData {
     pub total: u64,
     pub free: u64,
 }
 
+/// The `quota` facet of a drive. Fields may be hidden, eg. by some business accounts.
+#[derive(Debug, Default, Deserialize)]
+struct Quota {
+    total: Option<u64>,
+    remaining: Option<u64>,
+    used: Option<u64>,
+}
+
+impl StatfsData {
+    /// Reported if the quota is hidden, so that programs checking free space don't refuse
+    /// to write.
+    const UNKNOWN_SIZE: u64 = 1 << 50;
+
+    fn from_quota(quota: &Quota) -> Self {
+        let free = quota
+            .remaining
+            .or_else(|| Some(quota.total?.saturating_sub(quota.used?)))
+            .unwrap_or(Self::UNKNOWN_SIZE);
+        let total = quota
+            .total
+            .or_else(|| quota.used?.checked_add(free))
+            .unwrap_or(Self::UNKNOWN_SIZE);
+        Self { total, free }
+    }
+}
+
 impl Statfs {
     pub async fn new(onedrive: ManagedOnedrive, config: Config) -> Result<Self> {
         let data = Self::statfs_raw(&*onedrive.get().await).await?;
@@ -73,21 +99,95 @@ impl Statfs {
     async fn statfs_raw(onedrive: &OneDrive) -> Result<StatfsData> {
         use onedrive_api::{option::ObjectOption, resource::DriveField};
 
-        #[derive(Debug, Deserialize)]
-        struct Quota {
-            total: u64,
-            remaining: u64,
-            // used: u64,
-        }
-
         let drive = onedrive
             .get_drive_with_option(ObjectOption::new().select(&[DriveField::quota]))
             .await?;
-        let quota: Quota =
-            serde_json::from_value(*drive.quota.unwrap()).map_err(Error::Deserialize)?;
-        Ok(StatfsData {
-            total: quota.total,
-            free: quota.remaining,
-        })
+        let quota: Quota = match drive.quota {
+            Some(quota) => serde_json::from_value(*quota).map_err(Error::Deserialize)?,
+            None => {
+                log::debug!("Quota is not available");
+                Quota::default()
+            }
+        };
+        Ok(StatfsData::from_quota(&quota))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statfs(quota: serde_json::Value) -> StatfsData {
+        StatfsData::from_quota(&serde_json::from_value(quota).unwrap())
+    }
+
+    #[test]
+    fn full_quota() {
+        let data = statfs(serde_json::json!({ "total": 100, "remaining": 30, "used": 60 }));
+        // `remaining` is preferred over `total - used`.
+        assert_eq!(
+            data,
+            StatfsData {
+                total: 100,
+                free: 30
+            }
+        );
+    }
+
+    #[test]
+    fn quota_fallbacks() {
+        let data = statfs(serde_json::json!({ "total": 100, "used": 60 }));
+        assert_eq!(
+            data,
+            StatfsData {
+                total: 100,
+                free: 40
+            }
+        );
+        let data = statfs(serde_json::json!({ "remaining": 30, "used": 60 }));
+        assert_eq!(
+            data,
+            StatfsData {
+                total: 90,
+                free: 30
+            }
+        );
+        let data = statfs(serde_json::json!({ "total": 100, "used": 120 }));
+        assert_eq!(
+            data,
+            StatfsData {
+                total: 100,
+                free: 0
+            }
+        );
+    }
+
+    #[test]
+    fn hidden_quota() {
+        const UNKNOWN: u64 = StatfsData::UNKNOWN_SIZE;
+        let data = statfs(serde_json::json!({}));
+        assert_eq!(
+            data,
+            StatfsData {
+                total: UNKNOWN,
+                free: UNKNOWN
+            }
+        );
+        let data = statfs(serde_json::json!({ "total": 100 }));
+        assert_eq!(
+            data,
+            StatfsData {
+                total: 100,
+                free: UNKNOWN
+            }
+        );
+        let data = statfs(serde_json::json!({ "used": 60 }));
+        assert_eq!(
+            data,
+            StatfsData {
+                total: 60 + UNKNOWN,
+                free: UNKNOWN
+            }
+        );
     }
 }
@@ -0,0 +1,165 @@
+//! A virtual read-only file under the mount root reporting the current sync status.
+use crate::vfs::{
+    file::{ExtensionStats, FileStats, TransferKind},
+    inode_id::InodeStats,
+    InodeAttr,
+};
+use onedrive_api::ItemId;
+use serde::Deserialize;
+use std::{
+    fmt::Write as _,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    enable: bool,
+    file_name: String,
+    list_in_dir: bool,
+}
+
+pub struct StatusFile {
+    root_ino: u64,
+    mount_time: SystemTime,
+    config: Config,
+}
+
+/// Everything reported in the status file.
+pub struct Status {
+    pub syncing: bool,
+    /// Items with open handles, with their inodes.
+    pub open_files: Vec<(Option<u64>, ItemId)>,
+    pub file: FileStats,
+    /// Disk cache usage by file extensions, the largest first.
+    pub cache_by_extension: Vec<(String, ExtensionStats)>,
+    pub inodes: InodeStats,
+    pub time_since_last_sync: Option<Duration>,
+    pub last_error: Option<String>,
+}
+
+impl StatusFile {
+    /// The reserved inode number, which is never allocated by `InodeIdPool`.
+    pub const INO: u64 = u64::MAX - 1;
+
+    pub fn new(root_ino: u64, config: Config) -> Self {
+        Self {
+            root_ino,
+            mount_time: SystemTime::now(),
+            config,
+        }
+    }
+
+    /// Whether `name` under `parent_ino` refers to the status file.
+    pub fn matches(&self, parent_ino: u64, name: &str) -> bool {
+        self.config.enable && parent_ino == self.root_ino && name == self.config.file_name
+    }
+
+    /// Whether the status file should be listed as the first entry of directory `ino`.
+    pub fn listed_in(&self, ino: u64) -> bool {
+        self.config.enable && self.config.list_in_dir && ino == self.root_ino
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.config.file_name
+    }
+
+    pub fn attr(&self, content: &str) -> InodeAttr {
+        InodeAttr {
+            size: content.len() as u64,
+            mtime: SystemTime::now(),
+            crtime: self.mount_time,
+            is_directory: false,
+            c_tag: None,
+            dirty: false,
+            shared: None,
+            empty_dir: false,
+            e_tag: None,
+            remote_mtime: None,
+        }
+    }
+
+    pub fn render(status: &Status) -> String {
+        let mut buf = String::new();
+        let state = if status.syncing { "syncing" } else { "idle" };
+        writeln!(buf, "state: {}", state).unwrap();
+        writeln!(buf, "running_transfers: {}", status.file.running_transfers).unwrap();
+        for transfer in &status.file.transfers {
+            let kind = match transfer.kind {
+                TransferKind::Download => "download",
+                TransferKind::Upload => "upload",
+            };
+            let speed = match transfer.speed {
+                Some(speed) => format!("{:.0} B/s", speed),
+                None => "unknown speed".to_owned(),
+            };
+            write!(
+                buf,
+                "  {} {}: {} B at {}",
+                kind, transfer.item_id.0, transfer.bytes, speed,
+            )
+            .unwrap();
+            match transfer.progress {
+                // Empty files are done at once.
+                Some((done, total)) => {
+                    let percent = (done * 100).checked_div(total).unwrap_or(100);
+                    writeln!(buf, ", {}% of {} B", percent, total).unwrap();
+                }
+                None => writeln!(buf).unwrap(),
+            }
+        }
+        writeln!(buf, "dirty_files: {}", status.file.dirty_files).unwrap();
+        let quota_exceeded = if status.file.quota_exceeded {
+            "yes"
+        } else {
+            "no"
+        };
+        writeln!(buf, "quota_exceeded: {}", quota_exceeded).unwrap();
+        writeln!(buf, "open_files: {}", status.open_files.len()).unwrap();
+        for (ino, item_id) in &status.open_files {
+            match ino {
+                Some(ino) => writeln!(buf, "  {} (inode {})", item_id.0, ino).unwrap(),
+                None => writeln!(buf, "  {}", item_id.0).unwrap(),
+            }
+        }
+        writeln!(buf, "cached_files: {}", status.file.cached_files).unwrap();
+        writeln!(buf, "cached_bytes: {}", status.file.cached_size).unwrap();
+        writeln!(buf, "max_cached_bytes: {}", status.file.max_cached_size).unwrap();
+        writeln!(buf, "cache_hits: {}", status.file.cache_hits).unwrap();
+        writeln!(buf, "cache_misses: {}", status.file.cache_misses).unwrap();
+        writeln!(buf, "cache_evictions: {}", status.file.cache_evictions).unwrap();
+        writeln!(
+            buf,
+            "cache_by_extension: {}",
+            status.cache_by_extension.len()
+        )
+        .unwrap();
+        for (ext, stats) in &status.cache_by_extension {
+            writeln!(
+                buf,
+                "  {}: {} files, {} B, {} hits, {} misses, {} evictions",
+                ext,
+                stats.cached_files,
+                stats.cached_size,
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+            )
+            .unwrap();
+        }
+        writeln!(buf, "inodes: {}", status.inodes.alive).unwrap();
+        writeln!(buf, "inodes_peak: {}", status.inodes.peak).unwrap();
+        match status.time_since_last_sync {
+            Some(t) => writeln!(buf, "last_sync_secs_ago: {}", t.as_secs()).unwrap(),
+            None => writeln!(buf, "last_sync_secs_ago: disabled").unwrap(),
+        }
+        match &status.last_error {
+            Some(err) => writeln!(buf, "last_error: {}", err).unwrap(),
+            None => writeln!(buf, "last_error: none").unwrap(),
+        }
+        match &status.file.last_transfer_error {
+            Some(err) => writeln!(buf, "last_transfer_error: {}", err).unwrap(),
+            None => writeln!(buf, "last_transfer_error: none").unwrap(),
+        }
+        buf
+    }
+}
@@ -4,12 +4,13 @@ use onedrive_api::{
     resource::{DriveItem, DriveItemField},
     OneDrive,
 };
+use reqwest::StatusCode;
 use serde::Deserialize;
 use std::{
     collections::HashSet,
     num::NonZeroUsize,
     sync::{Arc, Mutex as SyncMutex, Weak},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::mpsc;
 
@@ -19,10 +20,41 @@ pub struct Config {
     #[serde(deserialize_with = "de_duration_sec")]
     period: Duration,
     fetch_page_size: NonZeroUsize,
+    detect_suspend: bool,
+}
+
+/// A time point measured by both the monotonic and the wall clock.
+///
+/// The monotonic clock may not advance during system suspend, which makes caches look fresh for
+/// far too long after a resume. The wall clock catches it up.
+#[derive(Debug, Clone, Copy)]
+struct SyncTime {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl SyncTime {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    fn elapsed(&self, detect_suspend: bool) -> Duration {
+        let elapsed = self.instant.elapsed();
+        if !detect_suspend {
+            return elapsed;
+        }
+        // Zero if the wall clock goes backward.
+        elapsed.max(self.wall.elapsed().unwrap_or_default())
+    }
 }
 
 pub struct Tracker {
-    last_sync_time: Option<Arc<SyncMutex<Instant>>>,
+    last_sync_time: Option<Arc<SyncMutex<SyncTime>>>,
+    /// The error of the last failed fetch, cleared after a successful one.
+    last_error: Arc<SyncMutex<Option<String>>>,
     config: Config,
 }
 
@@ -36,31 +68,44 @@ impl Tracker {
         let (weak, last_sync_time) = match config.enable {
             false => (Weak::new(), None),
             true => {
-                let arc = Arc::new(SyncMutex::new(Instant::now()));
+                let arc = Arc::new(SyncMutex::new(SyncTime::now()));
                 (Arc::downgrade(&arc), Some(arc))
             }
         };
 
+        let last_error = Arc::new(SyncMutex::new(None));
         tokio::spawn(tracking_thread(
             None,
             event_tx,
             select_fields,
             onedrive,
             weak,
+            Arc::downgrade(&last_error),
             config.clone(),
         ));
 
         Ok(Self {
             last_sync_time,
+            last_error,
             config,
         })
     }
 
     pub fn time_to_next_sync(&self) -> Option<Duration> {
-        let passed = self.last_sync_time.as_ref()?.lock().unwrap().elapsed();
+        let passed = self.time_since_last_sync()?;
         // Zero if time exceeded.
         Some(self.config.period.checked_sub(passed).unwrap_or_default())
     }
+
+    /// Time elapsed since the start of the last successful sync. `None` if tracking is disabled.
+    pub fn time_since_last_sync(&self) -> Option<Duration> {
+        let last_sync_time = *self.last_sync_time.as_ref()?.lock().unwrap();
+        Some(last_sync_time.elapsed(self.config.detect_suspend))
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
 }
 
 async fn tracking_thread(
@@ -68,18 +113,31 @@ async fn tracking_thread(
     event_tx: mpsc::Sender<UpdateEvent>,
     select_fields: Vec<DriveItemField>,
     onedrive: ManagedOnedrive,
-    last_sync_time: Weak<SyncMutex<Instant>>,
+    last_sync_time: Weak<SyncMutex<SyncTime>>,
+    last_error: Weak<SyncMutex<Option<String>>>,
     config: Config,
 ) {
     log::debug!("Tracking thread started");
 
+    let mut disconnected = false;
     loop {
         // Do the first fetch immediately.
-        let start_time = Instant::now();
+        let start_time = SyncTime::now();
 
         let onedrive = onedrive.get().await;
 
-        match fetch_changes(&mut delta_url, &select_fields, &onedrive, &config).await {
+        let ret = fetch_changes(&mut delta_url, &select_fields, &onedrive, &config).await;
+        match last_error.upgrade() {
+            Some(arc) => *arc.lock().unwrap() = ret.as_ref().err().map(|err| err.to_string()),
+            None => return,
+        }
+        if ret.is_ok()
+            && std::mem::take(&mut disconnected)
+            && event_tx.send(UpdateEvent::Reconnected).await.is_err()
+        {
+            return;
+        }
+        match ret {
             Ok(Some(changes)) => {
                 if event_tx
                     .send(UpdateEvent::BatchUpdate(changes))
@@ -93,6 +151,7 @@ async fn tracking_thread(
             Ok(None) => continue,
             Err(err) => {
                 log::error!("Failed to fetch changes: {}", err);
+                disconnected = true;
                 continue;
             }
         }
@@ -103,10 +162,32 @@ async fn tracking_thread(
         }
 
         // We don't need to catch up.
-        tokio::time::sleep(config.period).await;
+        if config.detect_suspend {
+            // Wake up periodically to check the wall clock, in case the system was suspended.
+            const SUSPEND_CHECK_PERIOD: Duration = Duration::from_secs(5);
+            loop {
+                let rest = match config.period.checked_sub(start_time.elapsed(true)) {
+                    Some(rest) if !rest.is_zero() => rest,
+                    _ => break,
+                };
+                tokio::time::sleep(rest.min(SUSPEND_CHECK_PERIOD)).await;
+            }
+        } else {
+            tokio::time::sleep(config.period).await;
+        }
     }
 }
 
+const MAX_THROTTLE_RETRY: u32 = 6;
+const THROTTLE_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+fn is_throttled(err: &onedrive_api::Error) -> bool {
+    matches!(
+        err.status_code(),
+        Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)
+    )
+}
+
 /// Fetch initial or delta changes with optional progress.
 ///
 /// Returns `Some(changes)` or `None` when delta url is gone.
@@ -133,7 +214,8 @@ async fn fetch_changes(
             log::debug!("Checking remote changes");
             match onedrive.track_root_changes_from_delta_url(url).await {
                 Ok(fetcher) => fetcher,
-                Err(err) if err.status_code().map_or(false, |st| st.is_client_error()) => {
+                Err(err) if is_throttled(&err) => return Err(err),
+                Err(err) if err.status_code().is_some_and(|st| st.is_client_error()) => {
                     log::info!("Re-sync required. Delta URL is gone: {}", err);
                     *delta_url = None;
                     return Ok(None);
@@ -147,7 +229,31 @@ async fn fetch_changes(
     let mut total_changes = 0usize;
     let mut ret = Vec::new();
     let mut seen_ids = HashSet::new();
-    while let Some(changes) = fetcher.fetch_next_page(onedrive).await? {
+    let mut throttled = 0u32;
+    loop {
+        // The fetcher keeps its position on failures, so the same page is retried
+        // without restarting the whole listing.
+        let changes = match fetcher.fetch_next_page(onedrive).await {
+            Ok(Some(changes)) => changes,
+            Ok(None) => break,
+            Err(err) if is_throttled(&err) && throttled < MAX_THROTTLE_RETRY => {
+                // `Retry-After` is not exposed in errors. Back off exponentially instead.
+                let delay = THROTTLE_INITIAL_DELAY * 2u32.pow(throttled);
+                throttled += 1;
+                log::warn!(
+                    "Throttled when fetching page {} of changes, retry {}/{} in {:?}: {}",
+                    page + 1,
+                    throttled,
+                    MAX_THROTTLE_RETRY,
+                    delay,
+                    err,
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        throttled = 0;
         total_changes += changes.len();
         page += 1;
 
@@ -0,0 +1,59 @@
+//! Version history of files, exposed as an extended attribute.
+use crate::vfs::error::{Error, Result};
+use onedrive_api::{ItemId, OneDrive};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::fmt::Write as _;
+
+const GRAPH_API_BASE: &str = "https://graph.microsoft.com/v1.0";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Version {
+    id: String,
+    #[serde(default)]
+    size: u64,
+    last_modified_date_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionCollection {
+    value: Vec<Version>,
+}
+
+/// Fetch and render the version history of a file, one version per line as
+/// `<version id> <size> <last modified time>`, newest first.
+/// It's empty if the file has no version history.
+///
+/// `drive_id` is the drive `onedrive` is built for, which is not exposed by `onedrive_api`.
+pub async fn render_versions(
+    onedrive: &OneDrive,
+    drive_id: &str,
+    item_id: &ItemId,
+) -> Result<String> {
+    // Not provided by `onedrive_api`.
+    let url = format!(
+        "{}/drives/{}/items/{}/versions",
+        GRAPH_API_BASE,
+        drive_id,
+        item_id.as_str(),
+    );
+    let resp = onedrive
+        .client()
+        .get(url)
+        .bearer_auth(onedrive.access_token())
+        .send()
+        .await?;
+    // The item is gone on remote side, rather than having no history.
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Err(Error::NotFound);
+    }
+    let versions: VersionCollection = resp.error_for_status()?.json().await?;
+
+    let mut buf = String::new();
+    for ver in versions.value {
+        let mtime = ver.last_modified_date_time.as_deref().unwrap_or("unknown");
+        writeln!(buf, "{} {} {}", ver.id, ver.size, mtime).unwrap();
+    }
+    Ok(buf)
+}